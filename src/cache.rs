@@ -0,0 +1,76 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+// (sstable filename, block offset)
+type BlockKey = (String, u64);
+
+/// least recently used cache of decompressed data blocks, shared by every sstable of an engine,
+/// bounded by the total byte size of the cached blocks
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>, // block, last access tick
+    lru: BTreeMap<u64, BlockKey>,                   // access tick -> block, oldest first
+    tick: u64,
+    usage: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    pub fn get(&self, filename: &str, offset: u64) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (filename.to_string(), offset);
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (block, last) = inner.blocks.get_mut(&key)?;
+        let block = Arc::clone(block);
+        let last = std::mem::replace(last, tick);
+        inner.lru.remove(&last);
+        inner.lru.insert(tick, key);
+        Some(block)
+    }
+
+    // blocks bigger than the whole cache are not kept
+    pub fn insert(&self, filename: &str, offset: u64, block: Arc<Vec<u8>>) {
+        if block.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let key = (filename.to_string(), offset);
+        inner.usage += block.len();
+        if let Some((old, last)) = inner.blocks.insert(key.clone(), (block, tick)) {
+            inner.usage -= old.len();
+            inner.lru.remove(&last);
+        }
+        inner.lru.insert(tick, key);
+
+        while inner.usage > self.capacity {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = inner.blocks.remove(&oldest) {
+                inner.usage -= evicted.len();
+            }
+        }
+    }
+
+    // byte size of the cached blocks
+    pub fn usage(&self) -> usize {
+        self.inner.lock().unwrap().usage
+    }
+}
@@ -0,0 +1,49 @@
+use log::{error, info};
+use std::sync::{Weak, mpsc};
+use std::time::Duration;
+
+use crate::engine::Engine;
+
+pub enum CatchUpMessage {
+    Shutdown, // sent by `Engine::close`
+}
+
+/// background loop of a secondary instance, picks up the sstables of the primary's
+/// newest version every `interval`
+pub struct CatchUp {
+    engine: Weak<Engine>, // doesn't keep the engine alive, so it can be dropped
+    rx: mpsc::Receiver<CatchUpMessage>,
+    interval: Duration,
+}
+
+impl CatchUp {
+    pub fn new(
+        engine: Weak<Engine>,
+        rx: mpsc::Receiver<CatchUpMessage>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            engine,
+            rx,
+            interval,
+        }
+    }
+
+    pub fn start_loop(&self) {
+        info!("catch up thread started");
+        loop {
+            match self.rx.recv_timeout(self.interval) {
+                Ok(CatchUpMessage::Shutdown) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            let Some(engine) = self.engine.upgrade() else {
+                break;
+            };
+            // the old version keeps serving reads, the next round retries
+            if let Err(err) = engine.try_catch_up_with_primary() {
+                error!("failed to catch up with primary: {:?}", err);
+            }
+        }
+        info!("catch up thread stopped");
+    }
+}
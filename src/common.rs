@@ -12,8 +12,27 @@ pub fn next_log_file_name(dir: &str) -> String {
     path.to_string_lossy().to_string()
 }
 
+/// true for the names given by `next_log_file_name`, other `.log` files don't belong to the engine
+pub fn is_log_file_name(filename: &str) -> bool {
+    let Some(name) = filename.strip_suffix(&format!(".{}", LOG_FILE_EXT)) else {
+        return false;
+    };
+    Uuid::try_parse(name)
+        .is_ok_and(|uuid| uuid.get_version_num() == 7 && uuid.hyphenated().to_string() == name)
+}
+
 #[derive(Error, Debug, PartialEq)]
 pub enum MossError {
     #[error("key not found")]
     KeyNotFound,
+    #[error("engine is closed")]
+    Closed,
+    #[error("background error: {0}")]
+    BackgroundError(String), // writes are rejected until `Engine::resume` succeeds
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String), // invalid options, or options incompatible with the database
+    #[error("engine is read-only")]
+    ReadOnly,
+    #[error("read error: {0}")]
+    ReadError(String), // an sstable holding the key can't be read, older values are not returned instead
 }
@@ -1,60 +1,169 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use log::{error, info};
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom},
-    sync::{Arc, mpsc},
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, Weak, mpsc},
+    thread,
 };
 
 use crate::{
-    common::next_log_file_name,
+    compaction::{CompactionTask, FilterDecision},
     engine::Engine,
-    layout::{BLOCK_SIZE_BYTES, Block, KVEntryReader},
-    sparseindex::SparseIndex,
-    sstable::SSTable,
+    merge::MergeIterator,
+    sstable::{IterError, SSTable, SSTableIterator},
     writer::Writer,
 };
 
+// reserves the inputs of compaction tasks, so two jobs never compact the same sstable
+#[derive(Debug, Default)]
+pub struct CompactionScheduler {
+    lock: Mutex<()>,   // picking and reserving happen under this lock
+    released: Condvar, // notified when a reservation is released
+}
+
+// inputs marked as being compacted, released on drop
+pub struct Reservation<'a> {
+    scheduler: &'a CompactionScheduler,
+    inputs: Vec<Arc<SSTable>>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let _guard = self.scheduler.lock.lock().unwrap();
+        for s in &self.inputs {
+            s.set_being_compacted(false);
+        }
+        self.scheduler.released.notify_all();
+    }
+}
+
+impl CompactionScheduler {
+    // pick a task and reserve its inputs, None if nothing to compact or an input is already reserved
+    pub fn schedule(
+        &self,
+        pick: impl FnOnce() -> Option<CompactionTask>,
+    ) -> Option<(CompactionTask, Reservation<'_>)> {
+        let _guard = self.lock.lock().unwrap();
+        let task = pick()?;
+        self.reserve(task)
+    }
+
+    // like `schedule`, but wait for running jobs to release the inputs, None if nothing to compact
+    pub fn schedule_wait(
+        &self,
+        mut pick: impl FnMut() -> Option<CompactionTask>,
+    ) -> Option<(CompactionTask, Reservation<'_>)> {
+        let mut guard = self.lock.lock().unwrap();
+        loop {
+            let task = pick()?;
+            if !task.inputs.iter().any(|s| s.is_being_compacted()) {
+                return self.reserve(task);
+            }
+            guard = self.released.wait(guard).unwrap();
+        }
+    }
+
+    // caller holds the lock
+    fn reserve(&self, task: CompactionTask) -> Option<(CompactionTask, Reservation<'_>)> {
+        if task.inputs.iter().any(|s| s.is_being_compacted()) {
+            return None;
+        }
+        for s in &task.inputs {
+            s.set_being_compacted(true);
+        }
+        let reservation = Reservation {
+            scheduler: self,
+            inputs: task.inputs.clone(),
+        };
+        Some((task, reservation))
+    }
+}
+
+pub enum CompactMessage {
+    Trigger,  // a new sstable file was generated, or another worker scheduled a job
+    Shutdown, // `Engine::close` sends one per worker
+}
+
+// a background compaction worker, `max_background_compactions` of them share the trigger channel
 pub struct Compact {
-    engine: Arc<Engine>,
-    rx: mpsc::Receiver<bool>, // value doesn't matter, msg itself indicates a new sstable file generates
+    engine: Weak<Engine>, // doesn't keep the engine alive, so it can be dropped
+    rx: Arc<Mutex<mpsc::Receiver<CompactMessage>>>,
+    tx: mpsc::Sender<CompactMessage>, // wakes another worker when a job is scheduled
 }
 
 impl Compact {
-    pub fn new(engine: Arc<Engine>, rx: mpsc::Receiver<bool>) -> Self {
-        Self { engine, rx }
+    pub fn new(
+        engine: Weak<Engine>,
+        rx: Arc<Mutex<mpsc::Receiver<CompactMessage>>>,
+        tx: mpsc::Sender<CompactMessage>,
+    ) -> Self {
+        Self { engine, rx, tx }
     }
 
     pub fn start_loop(&self) {
         info!("compact thread started");
         loop {
-            let _ = self.rx.recv();
+            let msg = self.rx.lock().unwrap().recv();
+            if !matches!(msg, Ok(CompactMessage::Trigger)) {
+                break;
+            }
+            let Some(engine) = self.engine.upgrade() else {
+                break;
+            };
             info!("compact thread received trigger message, try to find and compact files");
-            while self.get_sstable_size() > self.engine.sstable_compact_limit {
-                let sstables = self.get_sstables_to_compact();
-                if sstables.len() < 2 {
-                    info!("less than 2 sstables for compaction found, skip");
+            // a closed engine only waits for the running job, no new one is started,
+            // nor after an error until the engine is resumed
+            while !engine.is_closed() && engine.background_error().is_none() {
+                let scheduler = &engine.compaction_scheduler;
+                let Some((task, reservation)) =
+                    scheduler.schedule(|| Self::pick_compaction(&engine))
+                else {
                     break;
-                }
-                info!("found {} sstables to compact", sstables.len());
-                if let Err(err) = self.try_compact(sstables) {
-                    error!("try compact error: {:?}", err);
+                };
+                info!(
+                    "found {} sstables to compact into level {}",
+                    task.inputs.len(),
+                    task.output_level
+                );
+                // another worker may find a job not overlapping this one
+                let _ = self.tx.send(CompactMessage::Trigger);
+                // a panicking job must not take the worker down silently, writers would stall forever
+                let res =
+                    panic::catch_unwind(AssertUnwindSafe(|| Self::try_compact(&engine, task)))
+                        .unwrap_or_else(|_| Err(anyhow!("compaction thread panicked")));
+                // the inputs are only held by the versions of readers now
+                drop(reservation);
+                engine.purge_obsolete_files();
+                if let Err(err) = res {
+                    engine.set_background_error(format!("compaction failed: {:#}", err));
                     break;
                 }
             }
         }
+        info!("compact thread stopped");
+    }
+
+    fn pick_compaction(engine: &Engine) -> Option<CompactionTask> {
+        let version = Arc::clone(&engine.version.read().unwrap());
+        let options = &engine.options;
+        options.compaction_strategy.pick(&version, options)
     }
 
-    fn try_compact(&self, sstables: Vec<Arc<SSTable>>) -> Result<()> {
-        let filenames: Vec<String> = sstables.iter().map(|s| s.filename.clone()).collect();
+    pub fn try_compact(engine: &Engine, task: CompactionTask) -> Result<()> {
+        let filenames: Vec<String> = task.inputs.iter().map(|s| s.filename.clone()).collect();
         info!("compacting files: {:?}", filenames);
-        let res_file = self.compact(sstables);
+        let res_file = Self::compact(engine, &task);
         match res_file {
-            Err(err) => bail!("failed to compact files {:?}, error: {:?}", filenames, err),
+            Err(err) => bail!("failed to compact files {:?}, error: {:#}", filenames, err),
             Ok(res) => {
                 info!("compacted files {:?} to {:?}", filenames, res);
-                if let Err(err) = self.install_new_version(&filenames, &res) {
-                    bail!("failed to install new version after compaction: {:?}", err);
+                if let Err(err) =
+                    Self::install_new_version(engine, &filenames, &res, task.output_level)
+                {
+                    // never installed, the metadata file doesn't list them
+                    remove_outputs(&res);
+                    bail!("failed to install new version after compaction: {:#}", err);
                 } else {
                     info!("installed new version after compaction");
                     Ok(())
@@ -63,38 +172,58 @@ impl Compact {
         }
     }
 
-    fn install_new_version(&self, from: &[String], to: &str) -> Result<()> {
-        let sstable = Arc::new(SSTable::new(to)?);
+    fn install_new_version(
+        engine: &Engine,
+        from: &[String],
+        to: &[String],
+        output_level: usize,
+    ) -> Result<()> {
+        let mut sstables = vec![];
+        for filename in to {
+            let sstable = engine.open_sstable(filename)?;
+            // everything is deleted, the empty output file was never in the metadata file
+            if sstable.is_empty() {
+                drop(sstable);
+                if let Err(err) = fs::remove_file(filename) {
+                    error!(
+                        "failed to remove empty sstable file {}: {:?}",
+                        filename, err
+                    );
+                }
+            } else {
+                sstables.push(Arc::new(sstable));
+            }
+        }
         loop {
-            // read version and release lock
-            let mut version_ptr = std::ptr::null();
-            let mut version_sstable_len = 0;
-            let mut new_version = {
-                let version = self.engine.version.read().unwrap();
-                version_ptr = version.as_ref();
-                version_sstable_len = version.sstables.len();
-                let version = Arc::clone(&version);
-                (*version).clone()
-            };
+            // read version and release lock, the version is kept alive until compared
+            let version = Arc::clone(&engine.version.read().unwrap());
+            let version_sstable_len = version.sstables().count();
+            let mut new_version = (*version).clone();
 
-            // remove compacted sstables with the result sstable
+            // replace compacted sstables with the result sstables
             let first_replaced_idx = new_version
-                .sstables
+                .level(output_level)
                 .iter()
                 .position(|s| from.contains(&s.filename))
-                .unwrap();
-            new_version.sstables.retain(|s| !from.contains(&s.filename));
-            new_version
-                .sstables
-                .insert(first_replaced_idx, Arc::clone(&sstable));
-            let new_version_sstable_len = new_version.sstables.len();
-            assert!(new_version.sstables.len() < version_sstable_len);
+                .unwrap_or(new_version.level(output_level).len());
+            for level in new_version.levels.iter_mut() {
+                level.retain(|s| !from.contains(&s.filename));
+            }
+            let level = new_version.level_mut(output_level);
+            if output_level == 0 {
+                // level 0 is ordered by age, the results take the place of the inputs
+                // they never overlap each other, so their own order doesn't matter
+                level.splice(
+                    first_replaced_idx..first_replaced_idx,
+                    sstables.iter().cloned(),
+                );
+            } else {
+                level.extend(sstables.iter().cloned());
+                level.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+            }
+            let new_version_sstable_len = new_version.sstables().count();
 
-            if self
-                .engine
-                .install_new_version(version_ptr, Arc::new(new_version))
-                .is_ok()
-            {
+            if engine.install_new_version(&version, Arc::new(new_version))? {
                 info!(
                     "new version installed after compaction, old version sstable size = {}, new version sstable size = {}",
                     version_sstable_len, new_version_sstable_len,
@@ -104,205 +233,134 @@ impl Compact {
         }
     }
 
-    fn get_sstable_size(&self) -> usize {
-        let guard = self.engine.version.read().unwrap();
-        let version = Arc::clone(&guard);
-        version.sstables.len()
-    }
-
-    // TODO: improve this method, too many arc clone
-    /// current strategy: get the smallest two adjavent sstables, newest at the start
-    fn get_sstables_to_compact(&self) -> Vec<Arc<SSTable>> {
-        let guard = self.engine.version.read().unwrap();
-        let version = Arc::clone(&guard);
-
-        if version.sstables.len() < 2 {
-            return vec![];
+    // split by key range into subcompactions running in parallel, outputs of all parts are installed together
+    fn compact(engine: &Engine, task: &CompactionTask) -> Result<Vec<String>> {
+        let bounds = Self::subcompaction_bounds(engine, task);
+        if bounds.is_empty() {
+            return Self::compact_part(engine, task, None, None);
         }
-
-        // get the smallest size sstable
-        let mut sorted = version
-            .sstables
+        let lowers = std::iter::once(None).chain(bounds.iter().map(|b| Some(b.as_str())));
+        let uppers = bounds
             .iter()
-            .enumerate()
-            .map(|(idx, s)| (idx, s.file_size))
-            .collect::<Vec<(usize, u64)>>();
-        sorted.sort_by_cached_key(|(_, size)| *size);
-        let (idx, _) = &sorted[0];
+            .map(|b| Some(b.as_str()))
+            .chain(std::iter::once(None));
+        let parts: Vec<(Option<&str>, Option<&str>)> = lowers.zip(uppers).collect();
+        info!("compacting in {} subcompactions", parts.len());
 
-        // get the smaller ajacent sstable
-        if *idx == 0 {
-            return vec![
-                Arc::clone(&version.sstables[1]),
-                Arc::clone(&version.sstables[0]),
-            ];
-        }
-        if *idx == version.sstables.len() - 1 {
-            return vec![
-                Arc::clone(&version.sstables[*idx]),
-                Arc::clone(&version.sstables[*idx - 1]),
-            ];
-        }
-        if version.sstables[*idx - 1].file_size < version.sstables[*idx + 1].file_size {
-            vec![
-                Arc::clone(&version.sstables[*idx]),
-                Arc::clone(&version.sstables[*idx - 1]),
-            ]
-        } else {
-            vec![
-                Arc::clone(&version.sstables[*idx + 1]),
-                Arc::clone(&version.sstables[*idx]),
-            ]
-        }
-    }
-
-    fn compact(&self, sstables: Vec<Arc<SSTable>>) -> Result<String> {
-        let merge_iter = SSTableMergeIterator::new(sstables)?;
-        let filename = next_log_file_name(&self.engine.sstables_dir);
-        Writer::write(merge_iter, &filename)?;
-        Ok(filename)
-    }
-}
-
-struct SSTableMergeIterator {
-    files: Vec<File>,
-    sparseindex: Vec<SparseIndex>,
-    blocks: Vec<Block>,
-    block_index: Vec<usize>, // the index inside the sparse index, representing current block
-    offset_in_block: Vec<usize>,
-    heads: Vec<Option<(String, String, bool)>>, // key, val, deleted
-    loaded: bool,
-    prev: Option<String>, // previous outputed key, used to skip value that should be discarded
-}
-
-impl Iterator for SSTableMergeIterator {
-    type Item = (String, String, bool);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // initialize
-        if !self.loaded {
-            for idx in 0..self.files.len() {
-                self.load_next_block(idx).ok()?;
-                self.load_next_kv_for_block(idx).ok()?;
+        thread::scope(|scope| {
+            let handles: Vec<_> = parts
+                .into_iter()
+                .map(|(lower, upper)| {
+                    scope.spawn(move || Self::compact_part(engine, task, lower, upper))
+                })
+                .collect();
+            // every part is joined, the outputs of the others are removed if one fails
+            let mut filenames = vec![];
+            let mut first_err = None;
+            for handle in handles {
+                match handle.join() {
+                    Ok(Ok(res)) => filenames.extend(res),
+                    Ok(Err(err)) => {
+                        first_err.get_or_insert(err);
+                    }
+                    Err(_) => {
+                        first_err.get_or_insert(anyhow!("subcompaction thread panicked"));
+                    }
+                }
+            }
+            match first_err {
+                Some(err) => {
+                    remove_outputs(&filenames);
+                    Err(err)
+                }
+                None => Ok(filenames),
             }
-            self.loaded = true;
-        }
-
-        self.retrieve_next_not_deleted_unique_smallest()
-    }
-}
-
-impl SSTableMergeIterator {
-    // newest sstable should at the start
-    pub fn new(sstables: Vec<Arc<SSTable>>) -> Result<Self> {
-        let mut files = vec![];
-        for s in &sstables {
-            let file = OpenOptions::new().read(true).open(&s.filename)?;
-            files.push(file);
-        }
-        let len = files.len();
-
-        let sparseindex: Vec<SparseIndex> =
-            sstables.iter().map(|s| s.sparse_index.clone()).collect();
-
-        Ok(Self {
-            files,
-            blocks: vec![Block::new(); len],
-            offset_in_block: vec![0; len],
-            heads: vec![None; len],
-            loaded: false,
-            sparseindex,
-            block_index: vec![0; len],
-            prev: None,
         })
     }
 
-    pub fn retrieve_next_not_deleted_unique_smallest(&mut self) -> Option<(String, String, bool)> {
-        let mut cur = self.retrieve_next_unique_smallest()?;
-        // while deleted, skip all deleted value
-        while cur.2 {
-            cur = self.retrieve_next_unique_smallest()?;
-        }
-        Some(cur)
-    }
-
-    pub fn retrieve_next_unique_smallest(&mut self) -> Option<(String, String, bool)> {
-        let mut cur = self.retrieve_smallest()?;
-        if self.prev.is_none() || !self.prev.as_ref().unwrap().eq(&cur.0) {
-            self.prev = Some(cur.0.clone());
-            return Some(cur);
-        }
-        while self.prev.as_ref().unwrap().eq(&cur.0) {
-            cur = self.retrieve_smallest()?;
+    // split keys between subcompactions, taken evenly from the first keys of the input blocks
+    // outputs of level 0 stay in one part, like `target_file_size`
+    fn subcompaction_bounds(engine: &Engine, task: &CompactionTask) -> Vec<String> {
+        let max_parts = engine.options.max_subcompactions;
+        if max_parts <= 1 || task.output_level == 0 {
+            return vec![];
         }
-        self.prev = Some(cur.0.clone());
-        Some(cur)
+        let mut keys: Vec<&String> = task
+            .inputs
+            .iter()
+            .flat_map(|s| s.sparse_index.index.iter().map(|(k, _, _)| k))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        let parts = max_parts.min(keys.len());
+        let mut bounds: Vec<String> = (1..parts)
+            .map(|i| keys[i * keys.len() / parts].clone())
+            .collect();
+        bounds.dedup();
+        bounds
     }
 
-    // find the smallest, retrieve next
-    pub fn retrieve_smallest(&mut self) -> Option<(String, String, bool)> {
-        let min_idx = self
-            .heads
+    // compact the keys in [lower, upper) of the inputs
+    fn compact_part(
+        engine: &Engine,
+        task: &CompactionTask,
+        lower: Option<&str>,
+        upper: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let error = IterError::default();
+        let sources = task
+            .inputs
             .iter()
-            .enumerate()
-            .filter_map(|(idx, kv)| {
-                if kv.is_none() {
-                    return None;
-                }
-                Some(idx)
+            .map(|s| match lower {
+                Some(lower) => SSTableIterator::seek(Arc::clone(s), lower, error.clone()),
+                None => SSTableIterator::new(Arc::clone(s), error.clone()),
             })
-            .min_by(|a, b| {
-                self.heads[*a]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .cmp(&self.heads[*b].as_ref().unwrap().0)
-            })?;
-
-        // get the smallest, and retrieve the next element for it
-        let res = self.heads[min_idx].as_ref().unwrap().to_owned();
-        self.load_next_kv_for_block(min_idx).unwrap();
-        Some(res)
-    }
-
-    // Err => file format error
-    // Ok(bool) => true: read a block, false: no more block
-    pub fn load_next_block(&mut self, idx: usize) -> Result<bool> {
-        match self.sparseindex[idx].index.get(self.block_index[idx]) {
-            None => Ok(false),
-            Some((_, offset)) => {
-                self.files[idx].seek(SeekFrom::Start(*offset))?;
-                self.block_index[idx] += 1;
-                self.files[idx].read_exact(&mut self.blocks[idx].inner[..])?;
-                Ok(true)
+            .collect::<Result<Vec<_>>>()?;
+        let merge_iter = MergeIterator::new(sources, task.drop_tombstones)
+            .skip_while(|(k, _, _)| lower.is_some_and(|lower| k.as_str() < lower))
+            .take_while(|(k, _, _)| upper.is_none_or(|upper| k.as_str() < upper));
+        let filter = engine.options.compaction_filter.as_deref();
+        let filtered = merge_iter.filter_map(|(key, val, deleted)| {
+            let Some(filter) = filter.filter(|_| !deleted) else {
+                return Some((key, val, deleted));
+            };
+            match filter.filter(task.output_level, &key, &val) {
+                FilterDecision::Keep => Some((key, val, false)),
+                FilterDecision::ChangeValue(val) => Some((key, val, false)),
+                // older sstables outside the compaction may still hold the key, shadow it
+                FilterDecision::Remove if !task.drop_tombstones => Some((key, String::new(), true)),
+                FilterDecision::Remove => None,
             }
+        });
+        // level 0 bounds the file count, splitting there would never reduce it,
+        // only `compact_range` of level 0 files with `Leveled` gets here with the option set
+        let target_file_size = engine
+            .options
+            .target_file_size
+            .filter(|_| task.output_level > 0);
+        let filenames = Writer::write_split(
+            filtered,
+            &engine.sstables_dir,
+            &engine.options,
+            target_file_size,
+            engine.options.rate_limiter.clone(),
+        )?;
+        // the outputs miss the entries after the failed read, they are never installed
+        if let Err(err) = error.take_error() {
+            remove_outputs(&filenames);
+            return Err(err);
         }
+        Ok(filenames)
     }
+}
 
-    pub fn load_next_kv_for_block(&mut self, idx: usize) -> Result<()> {
-        if self.offset_in_block[idx] >= BLOCK_SIZE_BYTES {
-            match self.load_next_block(idx) {
-                Err(err) => return Err(err),
-                Ok(has_block) => {
-                    if !has_block {
-                        self.heads[idx] = None;
-                        return Ok(());
-                    }
-                    self.offset_in_block[idx] = 0;
-                }
-            }
-        }
-        let kv_entry = KVEntryReader::new(&self.blocks[idx].inner[self.offset_in_block[idx]..]);
-        if let Some((k, v, deleted, len)) = kv_entry.retrive_kv() {
-            self.heads[idx] = Some((
-                String::from_utf8_lossy(k).to_string(),
-                String::from_utf8_lossy(v).to_string(),
-                deleted,
-            ));
-            self.offset_in_block[idx] += len;
-        } else {
-            self.heads[idx] = None;
+// outputs of a failed compaction, not in any version, empty ones may be removed already
+fn remove_outputs(filenames: &[String]) {
+    for filename in filenames {
+        match fs::remove_file(filename) {
+            Ok(_) => info!("removed output file {} of failed compaction", filename),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => error!("failed to remove sstable file {}: {:?}", filename, err),
         }
-        Ok(())
     }
 }
@@ -0,0 +1,362 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{common::MossError, options::Options, sstable::SSTable, versionset::Version};
+
+/// a unit of compaction work
+pub struct CompactionTask {
+    pub inputs: Vec<Arc<SSTable>>, // newest at the start
+    pub output_level: usize,
+    pub drop_tombstones: bool, // true if no older sstable outside the inputs may hold the keys
+}
+
+impl CompactionTask {
+    /// every sstable overlapping [start, end], extended until no other sstable overlaps the inputs
+    /// the output goes to the deepest input level, nothing outside may hold the keys, tombstones are dropped
+    pub fn for_range(version: &Version, start: &str, end: &str) -> Option<Self> {
+        let (mut smallest, mut largest) = (start.to_string(), end.to_string());
+        let mut picked: Vec<(usize, Arc<SSTable>)> = vec![];
+        loop {
+            let mut grown = false;
+            for (level, s) in version.sstables() {
+                if !s.overlaps(&smallest, &largest)
+                    || picked.iter().any(|(_, p)| p.filename == s.filename)
+                {
+                    continue;
+                }
+                smallest = smallest.min(s.smallest_key.clone());
+                largest = largest.max(s.largest_key.clone());
+                picked.push((level, Arc::clone(s)));
+                grown = true;
+            }
+            if !grown {
+                break;
+            }
+        }
+        if picked.is_empty() {
+            return None;
+        }
+
+        // newest at the start: level 0 from the newest, then deeper levels
+        let output_level = picked.iter().map(|(level, _)| *level).max()?;
+        let mut inputs: Vec<Arc<SSTable>> = vec![];
+        for (level, sstables) in version.levels.iter().enumerate() {
+            let in_level = sstables
+                .iter()
+                .filter(|s| picked.iter().any(|(_, p)| p.filename == s.filename));
+            if level == 0 {
+                inputs.extend(in_level.rev().cloned());
+            } else {
+                inputs.extend(in_level.cloned());
+            }
+        }
+        Some(Self {
+            inputs,
+            output_level,
+            drop_tombstones: true,
+        })
+    }
+}
+
+/// decides which sstables to merge next, called by the compact threads until it returns None
+/// sstables being compacted by another job (`SSTable::is_being_compacted`) must not be picked
+pub trait CompactionStrategy: Debug + Send + Sync {
+    fn pick(&self, version: &Version, options: &Options) -> Option<CompactionTask>;
+
+    /// levels the strategy keeps sstables in, including level 0,
+    /// a database can't be reopened with a strategy covering fewer levels than it was written with
+    fn num_levels(&self) -> usize {
+        1
+    }
+
+    /// reject settings the strategy can't work with, called by `Options::validate`
+    fn validate(&self) -> Result<(), MossError> {
+        Ok(())
+    }
+}
+
+/// what to do with an entry coming out of a compaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Remove, // dropped, or written as a tombstone if older sstables may still hold the key
+    ChangeValue(String), // written with the new value
+}
+
+/// called for every live entry kept by a compaction, tombstones are not passed in
+/// an entry goes through every compaction it takes part in, so decisions should be idempotent
+pub trait CompactionFilter: Debug + Send + Sync {
+    fn filter(&self, output_level: usize, key: &str, value: &str) -> FilterDecision;
+}
+
+/// all sstables in level 0, merge the two smallest adjacent ones
+/// while the count is above `sstable_compact_limit`
+#[derive(Debug, Default)]
+pub struct AdjacentPairs;
+
+impl CompactionStrategy for AdjacentPairs {
+    fn pick(&self, version: &Version, options: &Options) -> Option<CompactionTask> {
+        let sstables = version.level(0);
+        if sstables.len() <= options.sstable_compact_limit {
+            return None;
+        }
+        let (newer, older) = Self::get_sstables_to_compact(sstables)?;
+        Some(CompactionTask {
+            inputs: vec![Arc::clone(&sstables[newer]), Arc::clone(&sstables[older])],
+            output_level: 0,
+            // tombstones can only be removed when no older sstable may still hold the key
+            drop_tombstones: older == 0,
+        })
+    }
+}
+
+impl AdjacentPairs {
+    /// current strategy: get the smallest two adjavent sstables
+    /// return (index of the newer one, index of the older one)
+    /// sstables being compacted are skipped
+    fn get_sstables_to_compact(sstables: &[Arc<SSTable>]) -> Option<(usize, usize)> {
+        if sstables.len() < 2 {
+            return None;
+        }
+        let free = |idx: usize| idx < sstables.len() && !sstables[idx].is_being_compacted();
+
+        // from the smallest size sstable
+        let mut sorted = (0..sstables.len())
+            .filter(|idx| free(*idx))
+            .map(|idx| (idx, sstables[idx].file_size))
+            .collect::<Vec<(usize, u64)>>();
+        sorted.sort_by_cached_key(|(_, size)| *size);
+
+        // get the smaller ajacent sstable
+        for (idx, _) in sorted {
+            let older = idx.checked_sub(1).filter(|older| free(*older));
+            let newer = Some(idx + 1).filter(|newer| free(*newer));
+            match (older, newer) {
+                (Some(older), Some(newer)) => {
+                    if sstables[older].file_size < sstables[newer].file_size {
+                        return Some((idx, older));
+                    }
+                    return Some((newer, idx));
+                }
+                (Some(older), None) => return Some((idx, older)),
+                (None, Some(newer)) => return Some((newer, idx)),
+                (None, None) => continue,
+            }
+        }
+        None
+    }
+}
+
+/// level 0 holds overlapping flushed sstables, scored by `sstable_compact_limit`,
+/// level 1..n hold non-overlapping sstables, each level is `level_size_multiplier` times bigger than the previous one
+#[derive(Debug)]
+pub struct Leveled {
+    pub max_bytes_for_level_base: u64, // target byte size of level 1
+    pub level_size_multiplier: u64,
+    pub num_levels: usize,                // including level 0
+    compact_pointers: Mutex<Vec<String>>, // per level, largest key of the last compacted sstable
+}
+
+impl Default for Leveled {
+    fn default() -> Self {
+        Self::new(256 * 1024 * 1024, 10, 7).unwrap() // 256 MB
+    }
+}
+
+impl Leveled {
+    /// at least level 0 and level 1, each level bigger than the previous one
+    pub fn new(
+        max_bytes_for_level_base: u64,
+        level_size_multiplier: u64,
+        num_levels: usize,
+    ) -> Result<Self, MossError> {
+        let leveled = Self {
+            max_bytes_for_level_base,
+            level_size_multiplier,
+            num_levels,
+            compact_pointers: Mutex::new(vec![]),
+        };
+        leveled.validate()?;
+        Ok(leveled)
+    }
+
+    // the first sstable after the one compacted last time, wrap around at the end of the level
+    // skip sstables being compacted, or overlapping ones being compacted in the next level
+    fn pick_round_robin(&self, version: &Version, level: usize) -> Option<Vec<Arc<SSTable>>> {
+        let mut pointers = self.compact_pointers.lock().unwrap();
+        if pointers.len() <= level {
+            pointers.resize(level + 1, String::new());
+        }
+        let sstables = version.level(level);
+        let start = sstables.partition_point(|s| s.smallest_key <= pointers[level]);
+        let (passed, pending) = sstables.split_at(start);
+        let inputs = pending.iter().chain(passed).find_map(|s| {
+            if s.is_being_compacted() {
+                return None;
+            }
+            let next = version.overlapping(level + 1, &s.smallest_key, &s.largest_key);
+            if next.iter().any(|n| n.is_being_compacted()) {
+                return None;
+            }
+            Some([vec![Arc::clone(s)], next].concat())
+        })?;
+        pointers[level] = inputs[0].largest_key.clone();
+        Some(inputs)
+    }
+
+    // level 0 and the overlapping part of level 1, none of them may be being compacted
+    fn pick_level0(version: &Version) -> Option<Vec<Arc<SSTable>>> {
+        let mut inputs: Vec<Arc<SSTable>> = version.level(0).iter().rev().cloned().collect();
+        let (smallest, largest) = key_range(&inputs)?;
+        inputs.extend(version.overlapping(1, &smallest, &largest));
+        if inputs.iter().any(|s| s.is_being_compacted()) {
+            return None;
+        }
+        Some(inputs)
+    }
+}
+
+impl CompactionStrategy for Leveled {
+    /// pick from the level with the highest score, level 0 is scored by file count, others by byte size
+    /// level 0 is merged as a whole into level 1, other levels pick one sstable round robin
+    /// levels with lower scores are tried if the inputs are being compacted
+    fn pick(&self, version: &Version, options: &Options) -> Option<CompactionTask> {
+        let mut scores = vec![(
+            0,
+            version.level(0).len() as f64 / options.sstable_compact_limit as f64,
+        )];
+        for level in 1..(self.num_levels - 1) {
+            // saturated, a level too big to ever fill is never compacted by size
+            let target = self
+                .max_bytes_for_level_base
+                .saturating_mul(self.level_size_multiplier.saturating_pow(level as u32 - 1));
+            scores.push((level, version.level_byte_size(level) as f64 / target as f64));
+        }
+        scores.retain(|(_, score)| *score > 1.0);
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        scores.into_iter().find_map(|(level, _)| {
+            let inputs = if level == 0 {
+                Self::pick_level0(version)?
+            } else {
+                self.pick_round_robin(version, level)?
+            };
+            let (smallest, largest) = key_range(&inputs)?;
+            let drop_tombstones = ((level + 2)..version.levels.len())
+                .all(|deeper| version.overlapping(deeper, &smallest, &largest).is_empty());
+            Some(CompactionTask {
+                inputs,
+                output_level: level + 1,
+                drop_tombstones,
+            })
+        })
+    }
+
+    fn num_levels(&self) -> usize {
+        self.num_levels
+    }
+
+    // the fields are public, so they are checked again when the engine is opened
+    fn validate(&self) -> Result<(), MossError> {
+        let invalid = |msg: String| Err(MossError::InvalidArgument(msg));
+        if self.num_levels < 2 {
+            return invalid(format!(
+                "num_levels must be at least 2, got {}",
+                self.num_levels
+            ));
+        }
+        if self.level_size_multiplier < 2 {
+            return invalid(format!(
+                "level_size_multiplier must be at least 2, got {}",
+                self.level_size_multiplier
+            ));
+        }
+        if self.max_bytes_for_level_base == 0 {
+            return invalid("max_bytes_for_level_base must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// all sstables in level 0, each one is a sorted run,
+/// merge runs of similar size while the count is above `sstable_compact_limit`,
+/// trades space for lower write amplification
+#[derive(Debug)]
+pub struct SizeTiered {
+    pub size_ratio: u64, // percent, a run joins the candidates if it is at most this much bigger than their total size
+    pub min_merge_width: usize,
+    pub max_merge_width: usize,
+}
+
+impl Default for SizeTiered {
+    fn default() -> Self {
+        Self {
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+        }
+    }
+}
+
+impl CompactionStrategy for SizeTiered {
+    fn pick(&self, version: &Version, options: &Options) -> Option<CompactionTask> {
+        let runs = version.level(0);
+        if runs.len() <= options.sstable_compact_limit || runs.len() < 2 {
+            return None;
+        }
+        let min_width = self.min_merge_width.max(2);
+        let max_width = self.max_merge_width.max(min_width);
+
+        // runs being compacted are never picked
+        let free = |idx: usize| !runs[idx].is_being_compacted();
+
+        // from the newest run, extend to older runs while they are of similar size
+        // picked runs are always adjacent, so the merged run takes their place in age order
+        let picked = (0..runs.len())
+            .rev()
+            .filter(|newest| free(*newest))
+            .find_map(|newest| {
+                let mut size = runs[newest].file_size;
+                let mut oldest = newest;
+                while oldest > 0 && newest - oldest + 1 < max_width && free(oldest - 1) {
+                    let next = runs[oldest - 1].file_size;
+                    if size * (100 + self.size_ratio) < next * 100 {
+                        break;
+                    }
+                    size += next;
+                    oldest -= 1;
+                }
+                (newest - oldest + 1 >= min_width).then_some((oldest, newest))
+            })
+            // no similar sized runs, merge the newest ones to keep the count bounded
+            .or(Some((runs.len().saturating_sub(min_width), runs.len() - 1)))
+            .filter(|(oldest, newest)| (*oldest..=*newest).all(free))?;
+
+        let (oldest, newest) = picked;
+        Some(CompactionTask {
+            inputs: runs[oldest..=newest].iter().rev().cloned().collect(),
+            output_level: 0,
+            drop_tombstones: oldest == 0,
+        })
+    }
+
+    fn validate(&self) -> Result<(), MossError> {
+        if self.min_merge_width < 2 || self.min_merge_width > self.max_merge_width {
+            return Err(MossError::InvalidArgument(format!(
+                "merge widths must satisfy 2 <= min_merge_width <= max_merge_width, got {} and {}",
+                self.min_merge_width, self.max_merge_width
+            )));
+        }
+        Ok(())
+    }
+}
+
+// (smallest key, largest key) of non empty sstables
+fn key_range(sstables: &[Arc<SSTable>]) -> Option<(String, String)> {
+    let non_empty = sstables.iter().filter(|s| !s.is_empty());
+    let smallest = non_empty.clone().map(|s| &s.smallest_key).min()?;
+    let largest = non_empty.map(|s| &s.largest_key).max()?;
+    Some((smallest.clone(), largest.clone()))
+}
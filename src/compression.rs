@@ -0,0 +1,62 @@
+use std::borrow::Cow;
+
+use anyhow::{Context, Result, bail};
+
+use crate::options::Compression;
+
+// byte layout of a data block on disk: [payload] [codec tag]
+// payload is the raw block, or the raw block compressed by the codec in the tag
+// the codec is chosen per block, a block that doesn't compress well is stored raw
+pub const CODEC_TAG_BYTES: usize = 1;
+const CODEC_TAG_NONE: u8 = 0;
+const CODEC_TAG_LZ4: u8 = 1;
+const CODEC_TAG_SNAPPY: u8 = 2;
+
+// store raw if compression saves less than 1/8 of the block
+const MIN_COMPRESSION_RATIO_DENOMINATOR: usize = 8;
+
+// encode a raw block into its on disk form
+pub fn compress_block(raw: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some((lz4_flex::compress_prepend_size(raw), CODEC_TAG_LZ4)),
+        Compression::Snappy => Some((
+            snap::raw::Encoder::new()
+                .compress_vec(raw)
+                .context("failed to compress block with snappy")?,
+            CODEC_TAG_SNAPPY,
+        )),
+    };
+
+    if let Some((mut data, tag)) = compressed
+        && data.len() < raw.len() - raw.len() / MIN_COMPRESSION_RATIO_DENOMINATOR
+    {
+        data.push(tag);
+        return Ok(data);
+    }
+
+    let mut stored = Vec::with_capacity(raw.len() + CODEC_TAG_BYTES);
+    stored.extend_from_slice(raw);
+    stored.push(CODEC_TAG_NONE);
+    Ok(stored)
+}
+
+// decode the on disk form of a block, raw blocks are borrowed without copy
+pub fn decompress_block(stored: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let Some((&tag, payload)) = stored.split_last() else {
+        bail!("empty block");
+    };
+    match tag {
+        CODEC_TAG_NONE => Ok(Cow::Borrowed(payload)),
+        CODEC_TAG_LZ4 => Ok(Cow::Owned(
+            lz4_flex::decompress_size_prepended(payload)
+                .context("failed to decompress lz4 block")?,
+        )),
+        CODEC_TAG_SNAPPY => Ok(Cow::Owned(
+            snap::raw::Decoder::new()
+                .decompress_vec(payload)
+                .context("failed to decompress snappy block")?,
+        )),
+        _ => bail!("unknown block codec tag {}", tag),
+    }
+}
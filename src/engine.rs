@@ -1,107 +1,318 @@
 use anyhow::{Context, Result, anyhow, bail};
-use log::info;
+use log::{error, info};
 use std::{
-    fs::{self, OpenOptions, read_to_string},
-    io::Write,
+    fs::{self, File, OpenOptions, read_to_string},
+    io::{self, Write},
     mem,
     path::PathBuf,
     sync::{
-        Arc, Mutex, RwLock,
+        Arc, Condvar, Mutex, RwLock, Weak,
+        atomic::{AtomicBool, Ordering},
         mpsc::{self},
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    common::MossError, compact::Compact, flush::Flush, memtable::MemTable, sstable::SSTable,
+    cache::BlockCache,
+    catchup::{CatchUp, CatchUpMessage},
+    common::{MossError, is_log_file_name},
+    compact::{Compact, CompactMessage, CompactionScheduler},
+    compaction::CompactionTask,
+    flush::{Flush, FlushMessage},
+    layout::{MAX_KEY_LEN, MAX_VAL_LEN},
+    memtable::{MemTable, new_memtable},
+    merge::{KVEntry, MergeIterator},
+    options::Options,
+    sstable::{IterError, SSTable, SSTableIterator},
+    statistics::Statistics,
     versionset::Version,
 };
 
 const METADATA_FILE: &str = "mossdb_metadata";
+const OPTIONS_FILE: &str = "mossdb_options";
+const LOCK_FILE: &str = "mossdb_lock"; // held by the primary while it is open
+const TMP_FILE_EXT: &str = "tmp";
+const CATCH_UP_RETRIES: usize = 3;
+const QUARANTINE_DIR: &str = "quarantine"; // orphaned files are moved here with `quarantine_orphan_files`
+const OBSOLETE_PREFIX: &str = "obsolete "; // metadata line of a removed sstable not deleted yet
+const SLOWDOWN_DELAY: Duration = Duration::from_millis(1); // delay of each write while slowed down
+const STOP_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// how the directory is shared with other engines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenMode {
+    Primary,   // the only writer, owns the files
+    ReadOnly,  // loads the version once
+    Secondary, // follows the primary's metadata file
+}
+
+/// files of the directory no version refers to, found and removed by `Engine::open`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OrphanReport {
+    pub sstables: Vec<String>, // missing from the metadata file, outputs of a flush or compaction cut short
+    pub temp_files: Vec<String>, // half-written metadata or options files
+    pub quarantine_dir: Option<String>, // where the files were moved, None if they were removed
+}
+
+// receiving ends of the background channels, each open mode starts the threads it needs
+struct Receivers {
+    flush: mpsc::Receiver<FlushMessage>,
+    compact: mpsc::Receiver<CompactMessage>,
+    catch_up: mpsc::Receiver<CatchUpMessage>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum WriteStall {
+    None,
+    Slowdown,
+    Stop,
+}
 
 #[derive(Debug)]
 pub struct Engine {
     pub version: RwLock<Arc<Version>>,
-    pub memtable: Mutex<MemTable>, // TODO: use concurrent data structure for better performance
+    // writers share the read lock and insert concurrently, the write lock is only taken to swap a full memtable
+    pub memtable: RwLock<Arc<dyn MemTable>>,
     pub sstables_dir: String,
-    pub memtable_flush_limit: usize, // trigger flush when memtable cross this number
-    pub sstable_compact_limit: usize, // trigger compact above the limit, will keep files under this number
-    flush_tx: mpsc::Sender<Arc<MemTable>>,
+    pub options: Options,
+    pub compaction_scheduler: CompactionScheduler, // background and manual compactions never share an input
+    pub statistics: Statistics,
+    block_cache: Option<Arc<BlockCache>>,
+    stall_lock: Mutex<()>,
+    stall_cv: Condvar, // notified when a new version is installed, stopped writers recheck
+    flush_tx: mpsc::Sender<FlushMessage>,
+    compact_tx: mpsc::Sender<CompactMessage>,
+    catch_up_tx: mpsc::Sender<CatchUpMessage>,
+    mode: OpenMode, // only a primary writes or deletes files in the directory
+    catch_up_lock: Mutex<()>, // a secondary never installs an older version over a newer one
+    obsolete_files: Mutex<Vec<(String, Weak<SSTable>)>>, // removed from the version, deleted once no reader holds them
+    orphan_report: OrphanReport,
+    closed: AtomicBool, // set under the memtable write lock, writes are rejected once set
+    background_error: Mutex<Option<String>>, // the first flush or compaction error, writes are rejected until resumed
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
+    compact_threads: Mutex<Vec<JoinHandle<()>>>,
+    catch_up_thread: Mutex<Option<JoinHandle<()>>>, // secondary only
+    lock_file: Mutex<Option<File>>,                 // primary only, released by `close`
 }
 
 impl Engine {
-    pub fn new(
-        path: &str,
-        memtable_flush_limit: usize,
-        sstable_compact_limit: usize,
-    ) -> Result<Arc<Engine>> {
+    /// open the database in the directory `path`, options are validated,
+    /// and checked against the options the database was last opened with
+    pub fn open(path: &str, options: Options) -> Result<Arc<Engine>> {
+        let (mut engine, rx) = Self::load(path, options, OpenMode::Primary)?;
+        engine.lock_dir()?;
+        engine.collect_obsolete_files()?;
+        // before the options file is written, a new database has no orphans
+        engine.orphan_report = engine.collect_orphan_files()?;
+        engine.write_options_file()?;
+        let compact_tx = engine.compact_tx.clone();
+
+        // start flush thread, background threads only hold weak references,
+        // so dropping the last user reference drops and closes the engine
+        let engine = Arc::new(engine);
+        let weak = Arc::downgrade(&engine);
+        let tx = compact_tx.clone();
+        let handle = thread::spawn(move || {
+            Flush::new(weak, rx.flush, tx).start_loop();
+        });
+        *engine.flush_thread.lock().unwrap() = Some(handle);
+
+        // start compaction threads
+        let compact_rx = Arc::new(Mutex::new(rx.compact));
+        for _ in 0..engine.options.max_background_compactions.max(1) {
+            let weak = Arc::downgrade(&engine);
+            let (rx, tx) = (compact_rx.clone(), compact_tx.clone());
+            let handle = thread::spawn(move || {
+                Compact::new(weak, rx, tx).start_loop();
+            });
+            engine.compact_threads.lock().unwrap().push(handle);
+        }
+
+        Ok(engine)
+    }
+
+    /// open an existing database in the directory `path` for reads only, the version is loaded
+    /// from the metadata file once, no background thread is started and no file is written or deleted,
+    /// so it is safe next to a writer process, writes, flushes and compactions return `ReadOnly`
+    pub fn open_read_only(path: &str, options: Options) -> Result<Arc<Engine>> {
+        let (engine, _) = Self::load(path, options, OpenMode::ReadOnly)?;
+        Ok(Arc::new(engine))
+    }
+
+    /// open an existing database in the directory `path` next to its primary, like a read-only engine,
+    /// but the primary's newest version is picked up every `catch_up_interval`, or on
+    /// `try_catch_up_with_primary`, entries still in the primary's memtables are not seen
+    pub fn open_as_secondary(path: &str, options: Options) -> Result<Arc<Engine>> {
+        let (engine, rx) = Self::load(path, options, OpenMode::Secondary)?;
+        let engine = Arc::new(engine);
+        let weak = Arc::downgrade(&engine);
+        let interval = engine.options.catch_up_interval;
+        let handle = thread::spawn(move || {
+            CatchUp::new(weak, rx.catch_up, interval).start_loop();
+        });
+        *engine.catch_up_thread.lock().unwrap() = Some(handle);
+        Ok(engine)
+    }
+
+    /// reload the version from the primary's metadata file, sstables already open are reused,
+    /// the current version is kept if the primary removed a listed file meanwhile and retries don't help
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        if self.mode != OpenMode::Secondary {
+            bail!(MossError::InvalidArgument(
+                "not a secondary instance".to_string()
+            ));
+        }
+        let _guard = self.catch_up_lock.lock().unwrap();
+        let mut last_err = None;
+        for _ in 0..CATCH_UP_RETRIES {
+            let current = Arc::clone(&self.version.read().unwrap());
+            match self.read_version(&current) {
+                Ok(version) => {
+                    *self.version.write().unwrap() = Arc::new(version);
+                    return Ok(());
+                }
+                // a compaction of the primary replaced the file after the metadata file was read
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    // the version listed in the metadata file, every file must exist
+    fn read_version(&self, current: &Version) -> Result<Version> {
+        let mut version = Version::new();
+        for (level, filename) in self.read_from_metadata_file() {
+            let path = self.db_file(&filename);
+            let file = path.to_string_lossy().to_string();
+            let sstable = match current.sstables().find(|(_, s)| s.filename == file) {
+                Some((_, s)) => Arc::clone(s),
+                None => Arc::new(self.open_sstable(&file)?),
+            };
+            version.level_mut(level).push(sstable);
+        }
+        Ok(version)
+    }
+
+    // validate the options and load the version, the receivers are for the background threads
+    fn load(path: &str, options: Options, mode: OpenMode) -> Result<(Engine, Receivers)> {
+        options.validate()?;
+        Self::prepare_dir(path, &options, mode)?;
+
         let (flush_tx, flush_rx) = mpsc::channel();
         let (compact_tx, compact_rx) = mpsc::channel();
+        let (catch_up_tx, catch_up_rx) = mpsc::channel();
+        let block_cache = (options.block_cache_size > 0)
+            .then(|| Arc::new(BlockCache::new(options.block_cache_size)));
 
         let mut engine = Self {
             version: RwLock::new(Arc::new(Version::new())),
-            memtable: Mutex::new(MemTable::new()),
+            memtable: RwLock::new(new_memtable(options.memtable)),
             sstables_dir: path.to_string(),
-            memtable_flush_limit,
-            sstable_compact_limit,
+            options,
+            compaction_scheduler: CompactionScheduler::default(),
+            statistics: Statistics::default(),
+            block_cache,
+            stall_lock: Mutex::new(()),
+            stall_cv: Condvar::new(),
             flush_tx,
+            compact_tx,
+            catch_up_tx,
+            mode,
+            catch_up_lock: Mutex::new(()),
+            obsolete_files: Mutex::new(vec![]),
+            orphan_report: OrphanReport::default(),
+            closed: AtomicBool::new(false),
+            background_error: Mutex::new(None),
+            flush_thread: Mutex::new(None),
+            compact_threads: Mutex::new(vec![]),
+            catch_up_thread: Mutex::new(None),
+            lock_file: Mutex::new(None),
         };
 
         // load all logs to sstable
         engine.open_log_dir(path)?;
-
-        // start flush thread
-        let engine = Arc::new(engine);
-        let cloned = engine.clone();
-        thread::spawn(move || {
-            Flush::new(cloned, flush_rx, compact_tx).start_loop();
-        });
-
-        // start compaction thread
-        let cloned = engine.clone();
-        thread::spawn(move || {
-            Compact::new(cloned, compact_rx).start_loop();
-        });
-
-        Ok(engine)
+        let rx = Receivers {
+            flush: flush_rx,
+            compact: compact_rx,
+            catch_up: catch_up_rx,
+        };
+        Ok((engine, rx))
     }
 
-    // TODO: may fail during writing metadata file, need to store the order in the sstable files too
-    // previous_version: compare and swap, used to compare
+    /// compare and swap, Ok(false) if the version is no longer `previous_version`, try again,
+    /// the metadata file is written first, if that fails the current version is kept
+    /// the caller keeps `previous_version` alive, so its address can't be reused by a newer version
     pub fn install_new_version(
         &self,
-        previous_version: *const Version,
+        previous_version: &Arc<Version>,
         new_version: Arc<Version>,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         let mut guard = self.version.write().unwrap();
         let current_version = guard.clone();
-        if std::ptr::eq(current_version.as_ref(), previous_version) {
-            let cloned = Arc::clone(&new_version);
-            *guard = new_version;
-            self.write_metadata_file(cloned);
-            return Ok(());
+        if !Arc::ptr_eq(&current_version, previous_version) {
+            return Ok(false);
         }
-        Err(anyhow!("previous version has changed, please try again"))
+        // recorded as obsolete in the same metadata file that drops them from the version
+        let removed: Vec<(String, Weak<SSTable>)> = current_version
+            .sstables()
+            .filter(|(_, s)| !new_version.sstables().any(|(_, n)| Arc::ptr_eq(s, n)))
+            .map(|(_, s)| (s.filename.clone(), Arc::downgrade(s)))
+            .collect();
+        self.write_metadata_file(&new_version, &removed)?;
+        self.obsolete_files.lock().unwrap().extend(removed);
+        *guard = new_version;
+        drop(guard);
+        drop(current_version);
+        self.purge_obsolete_files();
+        // background work may have caught up
+        let _stall_guard = self.stall_lock.lock().unwrap();
+        self.stall_cv.notify_all();
+        Ok(true)
     }
 
-    fn write_metadata_file(&self, version: Arc<Version>) {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(METADATA_FILE)
-            .unwrap();
-        let mut meta = vec![];
-        for s in &version.sstables {
+    // the pending obsolete files are listed along with the newly `removed` ones
+    fn write_metadata_file(
+        &self,
+        version: &Version,
+        removed: &[(String, Weak<SSTable>)],
+    ) -> Result<()> {
+        // one sstable per line: `level filename`
+        let mut meta = String::new();
+        for (level, s) in version.sstables() {
             let mut path = PathBuf::new();
             path.push(s.filename.clone());
             let filename = path.file_name().unwrap().to_string_lossy().to_string();
-            meta.push(filename.clone());
+            meta.push_str(&format!("{} {}\n", level, filename));
         }
-        for l in meta {
-            file.write_fmt(format_args!("{}\n", l)).unwrap();
+        let obsolete_files = self.obsolete_files.lock().unwrap();
+        for (filename, _) in obsolete_files.iter().chain(removed) {
+            let filename = PathBuf::from(filename);
+            let filename = filename.file_name().unwrap().to_string_lossy();
+            meta.push_str(&format!("{}{}\n", OBSOLETE_PREFIX, filename));
         }
+        drop(obsolete_files);
+        self.write_db_file(METADATA_FILE, &meta)
+            .context("cannot write metadata file")?;
         info!("metadata file written");
+        Ok(())
+    }
+
+    // write to a temporary file and rename it over `name`, readers of the directory,
+    // like a secondary instance, see either the old or the new content, never a partial one
+    fn write_db_file(&self, name: &str, content: &str) -> Result<()> {
+        let tmp = self.db_file(&format!("{}.{}", name, TMP_FILE_EXT));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&tmp)
+            .with_context(|| format!("cannot create {:?}", tmp))?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.db_file(name)).with_context(|| format!("cannot rename {:?}", tmp))?;
+        Ok(())
     }
 
     pub fn list_sorted_log_files(&self) -> Result<Vec<PathBuf>> {
@@ -112,7 +323,11 @@ impl Engine {
             bail!("not a directory");
         }
 
-        let filenames = self.read_from_metadata_file();
+        let filenames: Vec<String> = self
+            .read_from_metadata_file()
+            .into_iter()
+            .map(|(_, filename)| filename)
+            .collect();
 
         for entry in fs::read_dir(&path).context("cannot open log dir")? {
             let path = entry?.path();
@@ -122,7 +337,7 @@ impl Engine {
                     info!("recognizing {} in newest version", filename);
                     logs.push(path);
                 } else {
-                    info!("{} is not in newest version, please fix it", filename);
+                    info!("{} is not in newest version", filename);
                 }
             }
         }
@@ -132,22 +347,244 @@ impl Engine {
         Ok(logs)
     }
 
-    fn read_from_metadata_file(&self) -> Vec<String> {
-        let res = read_to_string(METADATA_FILE).unwrap_or_default();
-        res.lines().map(|s| s.to_string()).collect::<Vec<String>>()
+    // (level, filename) in metadata order, lines without a level belong to level 0
+    fn read_from_metadata_file(&self) -> Vec<(usize, String)> {
+        let res = read_to_string(self.db_file(METADATA_FILE)).unwrap_or_default();
+        res.lines()
+            .filter(|l| !l.starts_with(OBSOLETE_PREFIX))
+            .map(|l| match l.split_once(' ') {
+                Some((level, filename)) => (level.parse().unwrap_or(0), filename.to_string()),
+                None => (0, l.to_string()),
+            })
+            .collect()
+    }
+
+    /// delete the files removed from the version once no reader holds them,
+    /// only files whose removal is in the metadata file are deleted, the rest wait for the next call
+    pub fn purge_obsolete_files(&self) {
+        // only while the directory lock is held, after `close` another primary may own the files
+        if self.is_read_only() || self.lock_file.lock().unwrap().is_none() {
+            return;
+        }
+        self.obsolete_files
+            .lock()
+            .unwrap()
+            .retain(|(filename, sstable)| {
+                if sstable.strong_count() > 0 {
+                    return true;
+                }
+                match fs::remove_file(filename) {
+                    Ok(_) => info!("removed obsolete sstable file {}", filename),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        error!("failed to remove sstable file {}: {:?}", filename, err);
+                        return true;
+                    }
+                }
+                false
+            });
+    }
+
+    // files recorded as obsolete before a crash or before the last close, no reader holds them
+    fn collect_obsolete_files(&self) -> Result<()> {
+        let res = read_to_string(self.db_file(METADATA_FILE)).unwrap_or_default();
+        let obsolete: Vec<_> = res
+            .lines()
+            .filter_map(|l| l.strip_prefix(OBSOLETE_PREFIX))
+            .map(|filename| {
+                let file = self.db_file(filename).to_string_lossy().to_string();
+                (file, Weak::new())
+            })
+            .collect();
+        if obsolete.is_empty() {
+            return Ok(());
+        }
+        info!("collecting {} obsolete sstable files", obsolete.len());
+        self.obsolete_files.lock().unwrap().extend(obsolete);
+        self.purge_obsolete_files();
+        // drop the deleted ones from the metadata file
+        self.write_metadata_file(&self.version.read().unwrap(), &[])
+    }
+
+    // a second primary would delete the files the first one is writing
+    fn lock_dir(&self) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.db_file(LOCK_FILE))
+            .context("cannot open lock file")?;
+        if let Err(err) = file.try_lock() {
+            bail!(
+                "database {} is used by another engine: {}",
+                self.sstables_dir,
+                err
+            );
+        }
+        *self.lock_file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    // sstables missing from the metadata file and temp files, only the primary holding
+    // the lock writes them, so while it opens they belong to nothing,
+    // only names the engine gives its files are considered, anything else is left alone
+    fn collect_orphan_files(&self) -> Result<OrphanReport> {
+        let mut report = OrphanReport::default();
+        if !self.db_file(OPTIONS_FILE).is_file() && !self.db_file(METADATA_FILE).is_file() {
+            return Ok(report);
+        }
+        let live: Vec<String> = self
+            .read_from_metadata_file()
+            .into_iter()
+            .map(|(_, filename)| filename)
+            .collect();
+        let mut orphans = vec![];
+        for entry in fs::read_dir(&self.sstables_dir).context("cannot open log dir")? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let filename = path.file_name().unwrap().to_string_lossy().to_string();
+            let temp_files =
+                [METADATA_FILE, OPTIONS_FILE].map(|f| format!("{}.{}", f, TMP_FILE_EXT));
+            if is_log_file_name(&filename) {
+                if !live.contains(&filename) {
+                    report.sstables.push(filename.clone());
+                    orphans.push((path, filename));
+                }
+            } else if temp_files.contains(&filename) {
+                report.temp_files.push(filename.clone());
+                orphans.push((path, filename));
+            }
+        }
+        if orphans.is_empty() {
+            return Ok(report);
+        }
+
+        if self.options.quarantine_orphan_files {
+            let dir = self.db_file(QUARANTINE_DIR);
+            fs::create_dir_all(&dir).context("cannot create quarantine dir")?;
+            for (path, filename) in orphans {
+                fs::rename(&path, dir.join(filename))
+                    .with_context(|| format!("cannot quarantine {:?}", path))?;
+            }
+            report.quarantine_dir = Some(dir.to_string_lossy().to_string());
+        } else {
+            for (path, _) in orphans {
+                fs::remove_file(&path).with_context(|| format!("cannot remove {:?}", path))?;
+            }
+        }
+        info!(
+            "collected orphan files, sstables: {:?}, temp files: {:?}, quarantined in: {:?}",
+            report.sstables, report.temp_files, report.quarantine_dir
+        );
+        Ok(report)
+    }
+
+    /// the orphaned files found when the engine was opened
+    pub fn orphan_report(&self) -> &OrphanReport {
+        &self.orphan_report
+    }
+
+    // path of a file in the database directory
+    fn db_file(&self, name: &str) -> PathBuf {
+        PathBuf::from(&self.sstables_dir).join(name)
+    }
+
+    // create the directory of a new database, or check an existing one can be opened with the options
+    fn prepare_dir(path: &str, options: &Options, mode: OpenMode) -> Result<()> {
+        let dir = PathBuf::from(path);
+        let exists = dir.join(OPTIONS_FILE).is_file() || dir.join(METADATA_FILE).is_file();
+        if mode != OpenMode::Primary {
+            // nothing is created, and the levels are never compacted so any strategy works
+            if !exists {
+                bail!(MossError::InvalidArgument(format!(
+                    "database {} does not exist",
+                    path
+                )));
+            }
+            return Ok(());
+        }
+        if exists && options.error_if_exists {
+            bail!(MossError::InvalidArgument(format!(
+                "database {} exists (error_if_exists is true)",
+                path
+            )));
+        }
+        if !exists {
+            if !options.create_if_missing {
+                bail!(MossError::InvalidArgument(format!(
+                    "database {} does not exist (create_if_missing is false)",
+                    path
+                )));
+            }
+            fs::create_dir_all(&dir).context("cannot create database dir")?;
+            return Ok(());
+        }
+
+        // `key=value` lines, only settings deciding how existing files are handled are checked
+        let persisted = read_to_string(dir.join(OPTIONS_FILE)).unwrap_or_default();
+        for (key, value) in persisted.lines().filter_map(|l| l.split_once('=')) {
+            if key == "num_levels"
+                && let Ok(num_levels) = value.parse::<usize>()
+                && options.compaction_strategy.num_levels() < num_levels
+            {
+                bail!(MossError::InvalidArgument(format!(
+                    "database {} was written with {} levels, the compaction strategy only covers {}",
+                    path,
+                    num_levels,
+                    options.compaction_strategy.num_levels()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // the options of the last open, a database keeps the most levels it was ever opened with
+    fn write_options_file(&self) -> Result<()> {
+        let num_levels = self
+            .options
+            .compaction_strategy
+            .num_levels()
+            .max(self.version.read().unwrap().levels.len());
+        let options = &self.options;
+        let content = [
+            format!("num_levels={}", num_levels),
+            format!("block_size={}", options.block_size),
+            format!("compression={:?}", options.compression),
+            format!("filter_policy={:?}", options.filter_policy),
+            format!("memtable={:?}", options.memtable),
+        ];
+        self.write_db_file(OPTIONS_FILE, &(content.join("\n") + "\n"))
+            .context("cannot write options file")
+    }
+
+    /// bytes of decompressed blocks in the block cache
+    pub fn block_cache_usage(&self) -> usize {
+        self.block_cache.as_ref().map_or(0, |cache| cache.usage())
+    }
+
+    /// open an sstable of this engine with its read mode and block cache
+    pub fn open_sstable(&self, filename: &str) -> Result<SSTable> {
+        SSTable::new(filename, self.options.read_mode, self.block_cache.clone())
     }
 
     fn open_log_dir(&mut self, _: &str) -> Result<()> {
         let logs = self.list_sorted_log_files()?;
 
-        let mut sstables: Vec<Arc<SSTable>> = vec![];
-        for log in logs {
+        // sstables keep the metadata order inside each level
+        let mut new_version = Version::new();
+        for (level, filename) in self.read_from_metadata_file() {
+            let Some(log) = logs
+                .iter()
+                .find(|l| l.file_name().is_some_and(|f| *f == *filename))
+            else {
+                continue;
+            };
             let file = log.to_string_lossy().to_string();
-            sstables.push(Arc::new(SSTable::new(&file)?));
+            let sstable = Arc::new(self.open_sstable(&file)?);
+            new_version.level_mut(level).push(sstable);
         }
-
-        let mut new_version = Version::new();
-        new_version.sstables = sstables;
         let mut current = self.version.write().unwrap();
         *current = Arc::new(new_version);
 
@@ -155,16 +592,23 @@ impl Engine {
     }
 
     // set key value, append to log, udpate hash, grow if neccessary
-    pub fn put(&self, key: &str, value: &str) {
-        self.flush_if(move |m: &mut MemTable| {
-            m.put(key.to_string(), value.to_string());
-            m.byte_size() >= self.memtable_flush_limit
-        });
+    pub fn put(&self, key: &str, value: &str) -> std::result::Result<(), MossError> {
+        self.stall_if_needed();
+        self.write(key, value, |m| m.put(key, value))
     }
 
     // get value, check hash to find offset in log
     pub fn get(&self, key: &str) -> std::result::Result<String, MossError> {
-        let memtable = self.memtable.lock().unwrap();
+        // taken together under the memtable lock, a swapped out memtable is already in the version
+        // the lock is only held to clone the two pointers, lookups never block writers
+        let (memtable, version) = {
+            let memtable = self.memtable.read().unwrap();
+            (
+                Arc::clone(&memtable),
+                Arc::clone(&self.version.read().unwrap()),
+            )
+        };
+
         if let Some((value, deleted)) = memtable.get(key) {
             if deleted {
                 return Err(MossError::KeyNotFound);
@@ -172,9 +616,6 @@ impl Engine {
             return Ok(value);
         }
 
-        let version = self.version.read().unwrap();
-        let version = Arc::clone(&version);
-
         for m in version.imm_memtables.iter().rev() {
             if let Some((value, deleted)) = m.get(key) {
                 if deleted {
@@ -184,8 +625,9 @@ impl Engine {
             }
         }
 
-        for t in version.sstables.iter().rev() {
-            if let Ok((val, deleted)) = t.get(key) {
+        // level 0 may overlap, newest first
+        for t in version.level(0).iter().rev() {
+            if let Some((val, deleted)) = Self::get_from_sstable(t, key)? {
                 if deleted {
                     return Err(MossError::KeyNotFound);
                 }
@@ -193,78 +635,376 @@ impl Engine {
             }
         }
 
-        return Err(MossError::KeyNotFound);
+        // deeper levels hold at most one sstable containing the key
+        for level in version.levels.iter().skip(1) {
+            let idx = level.partition_point(|t| t.largest_key.as_str() < key);
+            let Some(t) = level.get(idx) else {
+                continue;
+            };
+            if let Some((val, deleted)) = Self::get_from_sstable(t, key)? {
+                if deleted {
+                    return Err(MossError::KeyNotFound);
+                }
+                return Ok(val);
+            }
+        }
+
+        Err(MossError::KeyNotFound)
+    }
+
+    // a newer sstable that can't be read may hide the value, the error is returned instead
+    fn get_from_sstable(
+        sstable: &SSTable,
+        key: &str,
+    ) -> std::result::Result<Option<(String, bool)>, MossError> {
+        sstable
+            .get(key)
+            .map_err(|err| MossError::ReadError(format!("{:#}", err)))
     }
 
     // delete key, the tombstone value is an empty byte array
-    pub fn del(&self, key: &str) {
-        self.flush_if(move |m: &mut MemTable| {
-            m.del(key.to_string());
-            m.byte_size() >= self.memtable_flush_limit
-        });
+    pub fn del(&self, key: &str) -> std::result::Result<(), MossError> {
+        self.stall_if_needed();
+        self.write(key, "", |m| m.del(key))
     }
 
-    /// flush immedieately to disk
-    pub fn flush(&self) {
+    // write to the memtable under the shared lock, concurrent writers don't block each other
+    // the memtable can't be swapped out during the write, so no write is lost
+    fn write<F>(&self, key: &str, value: &str, write: F) -> std::result::Result<(), MossError>
+    where
+        F: FnOnce(&dyn MemTable),
+    {
+        if self.is_read_only() {
+            return Err(MossError::ReadOnly);
+        }
+        // checked before the memtable, the flush would fail on it and every later write with it
+        if key.len() > MAX_KEY_LEN || value.len() > MAX_VAL_LEN {
+            return Err(MossError::InvalidArgument(format!(
+                "key and value must be at most {} and {} bytes, got {} and {}",
+                MAX_KEY_LEN,
+                MAX_VAL_LEN,
+                key.len(),
+                value.len()
+            )));
+        }
+        let is_full = {
+            let memtable = self.memtable.read().unwrap();
+            // checked under the lock, `close` flushes the memtable after the flag is set
+            if self.is_closed() {
+                return Err(MossError::Closed);
+            }
+            if let Some(err) = self.background_error() {
+                return Err(MossError::BackgroundError(err));
+            }
+            write(memtable.as_ref());
+            memtable.byte_size() >= self.options.memtable_flush_limit
+        };
+        if is_full {
+            self.flush_if(|m| m.byte_size() >= self.options.memtable_flush_limit);
+        }
+        Ok(())
+    }
+
+    fn write_stall(&self) -> WriteStall {
+        let version = self.version.read().unwrap();
+        let imm_memtables = version.imm_memtables.len();
+        let level0_files = version.level(0).len();
+        let max_imm_memtables = self.options.max_imm_memtables.max(1);
+        // compaction only starts above sstable_compact_limit, never wait for less than that
+        let level0_floor = self.options.sstable_compact_limit + 1;
+        let level0_stop_files = self.options.level0_stop_files.max(level0_floor);
+        let level0_slowdown_files = self.options.level0_slowdown_files.max(level0_floor);
+        if imm_memtables >= max_imm_memtables || level0_files >= level0_stop_files {
+            WriteStall::Stop
+        } else if (max_imm_memtables > 2 && imm_memtables + 1 >= max_imm_memtables)
+            || level0_files >= level0_slowdown_files
+        {
+            WriteStall::Slowdown
+        } else {
+            WriteStall::None
+        }
+    }
+
+    // slow down writers when flush or compaction falls behind, block them when it falls too far
+    fn stall_if_needed(&self) {
+        // nothing drains a read-only or secondary engine, the write is rejected right away
+        if self.is_read_only() {
+            return;
+        }
+        let start = Instant::now();
+        match self.write_stall() {
+            WriteStall::None => {}
+            WriteStall::Slowdown => {
+                thread::sleep(SLOWDOWN_DELAY);
+                self.statistics.record_slowdown(start.elapsed());
+            }
+            WriteStall::Stop => {
+                let mut guard = self.stall_lock.lock().unwrap();
+                // nothing drains while closed or after a background error, the write is rejected instead
+                while !self.is_closed()
+                    && self.background_error().is_none()
+                    && self.write_stall() == WriteStall::Stop
+                {
+                    guard = self
+                        .stall_cv
+                        .wait_timeout(guard, STOP_RECHECK_INTERVAL)
+                        .unwrap()
+                        .0;
+                }
+                self.statistics.record_stop(start.elapsed());
+            }
+        }
+    }
+
+    /// flush the memtable to disk, return once every memtable written before the call
+    /// is in an sstable installed in the version, or the error if flushing failed
+    pub fn flush(&self) -> Result<()> {
+        self.flush_until(None)
+    }
+
+    /// like `flush`, but give up waiting after `timeout`, the flush itself goes on
+    pub fn flush_wait(&self, timeout: Duration) -> Result<()> {
+        self.flush_until(Some(timeout))
+    }
+
+    fn flush_until(&self, timeout: Option<Duration>) -> Result<()> {
+        self.check_background_work()?;
         self.flush_if(|m| m.byte_size() > 0);
+
+        // the flush thread handles messages in order, so the answer comes after the memtable is flushed
+        let (tx, rx) = mpsc::channel();
+        self.flush_tx.send(FlushMessage::Wait(tx))?;
+        let res = match timeout {
+            None => rx.recv()?,
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(res) => res,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    bail!("flush not finished within {:?}", timeout)
+                }
+                Err(err) => bail!(err),
+            },
+        };
+        res.map_err(|err| anyhow!(MossError::BackgroundError(err)))
     }
 
     /// flush current memtable immediately to disk if predicate is true
-    /// inside a mutext lock, so that flushing the correct one
+    /// inside the memtable write lock, so that flushing the correct one
     fn flush_if<F>(&self, predicate: F)
     where
-        F: FnOnce(&mut MemTable) -> bool,
+        F: FnOnce(&dyn MemTable) -> bool,
     {
-        let mut memtable = self.memtable.lock().unwrap();
-        if predicate(&mut memtable) {
-            // replace full memtable with a new one
-            let old_memtable = mem::replace(&mut *memtable, MemTable::new());
-            let old_memtable = Arc::new(old_memtable);
-            drop(memtable);
-
-            // install the full memtable to the newest version
-            // use optimistic lock: cmpare and set
-            // reason: full memtable installation is rare compare to read operation
-            // optimistic lock is more performant
-            // and cloning and push cost time when the vector is long
-            // a simple mutex will block read operation for a long time
-            loop {
-                let mut version_ptr = std::ptr::null();
-                // cheap read lock
-                let mut new_version = {
-                    // put version in a block to realease the read lock upon block end
-                    let version = self.version.read().unwrap().clone();
-                    version_ptr = version.as_ref();
-                    (*version).clone()
-                };
-                new_version.imm_memtables.push(old_memtable.clone());
-
-                // write lock with cheap operation
-                let mut guard = self.version.write().unwrap();
-                let current_version = Arc::clone(&guard);
-                if std::ptr::eq(current_version.as_ref(), version_ptr) {
-                    *guard = Arc::new(new_version);
-                    break;
-                }
+        let mut memtable = self.memtable.write().unwrap();
+        if !predicate(memtable.as_ref()) {
+            return;
+        }
+
+        // install the full memtable to the newest version
+        // use optimistic lock: cmpare and set
+        // reason: full memtable installation is rare compare to read operation
+        // optimistic lock is more performant
+        // and cloning and push cost time when the vector is long
+        // a simple mutex will block read operation for a long time
+        // the memtable write lock is still held, readers see it either as memtable or immutable memtable
+        loop {
+            // cheap read lock, the version is kept alive until compared
+            let version = Arc::clone(&self.version.read().unwrap());
+            let mut new_version = (*version).clone();
+            new_version.imm_memtables.push(Arc::clone(&memtable));
+
+            // write lock with cheap operation
+            let mut guard = self.version.write().unwrap();
+            if Arc::ptr_eq(&guard, &version) {
+                *guard = Arc::new(new_version);
+                break;
             }
+        }
 
-            // notify flush thread
-            let _ = self.flush_tx.send(old_memtable.clone());
+        // replace full memtable with a new one
+        let old_memtable = mem::replace(&mut *memtable, new_memtable(self.options.memtable));
+        drop(memtable);
+
+        // notify flush thread
+        let _ = self.flush_tx.send(FlushMessage::Memtable(old_memtable));
+    }
+
+    /// key value pairs with keys in [start, end] sorted by key, deleted keys are skipped
+    pub fn scan(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        // memtable and version are read under the memtable lock as a consistent snapshot
+        let (memtable, version) = {
+            let memtable = self.memtable.read().unwrap();
+            let entries: Vec<KVEntry> = memtable.range(start, end).collect();
+            (entries, Arc::clone(&self.version.read().unwrap()))
+        };
+
+        // newest source at the start
+        let error = IterError::default();
+        let mut sources: Vec<Box<dyn Iterator<Item = KVEntry> + '_>> =
+            vec![Box::new(memtable.into_iter())];
+        for m in version.imm_memtables.iter().rev() {
+            sources.push(Box::new(m.range(start, end)));
+        }
+        for s in version.overlapping(0, start, end).into_iter().rev() {
+            sources.push(Box::new(SSTableIterator::seek(s, start, error.clone())?));
+        }
+        for level in 1..version.levels.len() {
+            for s in version.overlapping(level, start, end) {
+                sources.push(Box::new(SSTableIterator::seek(s, start, error.clone())?));
+            }
+        }
+
+        let kvs = MergeIterator::new(sources, true)
+            .skip_while(|(k, _, _)| k.as_str() < start)
+            .take_while(|(k, _, _)| k.as_str() <= end)
+            .map(|(k, v, _)| (k, v))
+            .collect();
+        error.take_error()?;
+        Ok(kvs)
+    }
+
+    /// merge every sstable overlapping [start, end] and drop its tombstones,
+    /// returns after the new version is installed, entries still in memtables are not included
+    pub fn compact_range(&self, start: &str, end: &str) -> Result<()> {
+        // a closed engine gave up the directory, another primary may own the files
+        self.check_background_work()?;
+        // wait for background jobs holding any of the sstables
+        let Some((task, reservation)) = self.compaction_scheduler.schedule_wait(|| {
+            let version = Arc::clone(&self.version.read().unwrap());
+            CompactionTask::for_range(&version, start, end)
+        }) else {
+            info!("no sstable overlaps range [{}, {}]", start, end);
+            return Ok(());
+        };
+        let res = Compact::try_compact(self, task);
+        drop(reservation);
+        self.purge_obsolete_files();
+        res
+    }
+
+    // flushes, compactions and resume need the background threads
+    fn check_background_work(&self) -> Result<()> {
+        if self.is_read_only() {
+            bail!(MossError::ReadOnly);
+        }
+        if self.is_closed() {
+            bail!(MossError::Closed);
+        }
+        Ok(())
+    }
+
+    /// true for read-only and secondary engines
+    pub fn is_read_only(&self) -> bool {
+        self.mode != OpenMode::Primary
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// the error that stopped flushes or compactions, None if healthy
+    pub fn background_error(&self) -> Option<String> {
+        self.background_error.lock().unwrap().clone()
+    }
+
+    // sticky, only the first error is kept
+    pub(crate) fn set_background_error(&self, err: String) {
+        error!("background error: {}", err);
+        self.background_error.lock().unwrap().get_or_insert(err);
+        // stopped writers return the error instead of waiting
+        self.stall_cv.notify_all();
+    }
+
+    // only after `resume` succeeded
+    pub(crate) fn clear_background_error(&self) {
+        *self.background_error.lock().unwrap() = None;
+    }
+
+    /// retry the memtables left by a failed flush and restart compactions, once the cause is fixed,
+    /// writes are accepted again if it succeeds, the background error is kept otherwise
+    pub fn resume(&self) -> Result<()> {
+        self.check_background_work()?;
+        // the flush thread retries, so memtables are never flushed twice or out of order
+        let (tx, rx) = mpsc::channel();
+        self.flush_tx.send(FlushMessage::Resume(tx))?;
+        match rx.recv()? {
+            Ok(_) => {
+                info!("engine resumed");
+                Ok(())
+            }
+            Err(err) => bail!(MossError::BackgroundError(err)),
+        }
+    }
+
+    /// stop accepting writes, flush every memtable, wait for the running compactions,
+    /// then stop the background threads, later calls return immediately,
+    /// reads still work afterwards, also called when the engine is dropped
+    pub fn close(&self) -> Result<()> {
+        {
+            let _memtable = self.memtable.write().unwrap();
+            if self.closed.swap(true, Ordering::AcqRel) {
+                return Ok(());
+            }
+        }
+        info!("closing engine");
+        self.flush_if(|m| m.byte_size() > 0);
+
+        // memtables queued before the shutdown message are flushed first
+        let _ = self.flush_tx.send(FlushMessage::Shutdown);
+        if let Some(handle) = self.flush_thread.lock().unwrap().take() {
+            join_background_thread(handle);
+        }
+        let compact_threads = mem::take(&mut *self.compact_threads.lock().unwrap());
+        for _ in 0..compact_threads.len() {
+            let _ = self.compact_tx.send(CompactMessage::Shutdown);
+        }
+        for handle in compact_threads {
+            join_background_thread(handle);
+        }
+        let _ = self.catch_up_tx.send(CatchUpMessage::Shutdown);
+        if let Some(handle) = self.catch_up_thread.lock().unwrap().take() {
+            join_background_thread(handle);
         }
+        self.stall_cv.notify_all();
+
+        // left over by a failed flush, or by a flush thread stopped while the engine was dropped
+        Flush::flush_all(self)?;
+        // files still held by readers stay in the metadata file, the next open deletes them
+        self.purge_obsolete_files();
+        // nothing is written anymore, another primary may open the directory
+        self.lock_file.lock().unwrap().take();
+        info!("engine closed");
+        Ok(())
     }
 
     pub fn dump(&self) {
-        let memtable = self.memtable.lock().unwrap();
+        let memtable = self.memtable.read().unwrap();
         println!("memtable = {:?}", memtable);
 
         let version = self.version.read().unwrap();
         let version = Arc::new(&version);
         println!("immutable memtables = {:?}", version.imm_memtables);
-        println!("sstables = {:?}", version.sstables);
+        println!("levels = {:?}", version.levels);
 
-        for s in &version.sstables {
-            println!("SSTable<{}>", s.filename);
+        for (level, s) in version.sstables() {
+            println!("SSTable<{}> level {}", s.filename, level);
             s.dump();
         }
     }
 }
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            error!("error when closing engine: {:?}", err);
+        }
+    }
+}
+
+// the last reference may be dropped by a background thread, which can't join itself
+fn join_background_thread(handle: JoinHandle<()>) {
+    if handle.thread().id() == thread::current().id() {
+        return;
+    }
+    if handle.join().is_err() {
+        error!("background thread panicked");
+    }
+}
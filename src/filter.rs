@@ -0,0 +1,84 @@
+// byte layout of a filter block: [bit array] [probe count]
+// a bloom filter over every key of the sstable, probes use double hashing of a single 64 bit hash
+// the probe count is stored, so files written with different `bits_per_key` can be mixed
+const PROBE_COUNT_BYTES: usize = 1;
+
+// FNV-1a, stable across builds, the filter is persisted
+fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+// collects key hashes while an sstable is written
+pub struct FilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    pub fn new(bits_per_key: usize) -> Self {
+        Self {
+            bits_per_key,
+            hashes: vec![],
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        // ln 2 * bits per key probes minimize the false positive rate
+        let probes = ((self.bits_per_key as f64 * 0.69) as usize).clamp(1, 30);
+        let bits = (self.hashes.len() * self.bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut data = vec![0_u8; bytes + PROBE_COUNT_BYTES];
+        for h in &self.hashes {
+            for pos in probe_positions(*h, probes, bits) {
+                data[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        data[bytes] = probes as u8;
+        data
+    }
+}
+
+// a decoded filter block
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    data: Vec<u8>, // bit array
+    probes: usize,
+}
+
+impl BloomFilter {
+    // None for an empty or malformed block, every key may be in the sstable then
+    pub fn decode(block: &[u8]) -> Option<Self> {
+        let (&probes, data) = block.split_last()?;
+        if data.is_empty() || probes == 0 {
+            return None;
+        }
+        Some(Self {
+            data: data.to_vec(),
+            probes: probes as usize,
+        })
+    }
+
+    // false if the key is definitely not in the sstable
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let bits = self.data.len() * 8;
+        probe_positions(hash(key), self.probes, bits)
+            .all(|pos| self.data[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+fn probe_positions(h: u64, probes: usize, bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = h as u32 as u64;
+    let delta = (h >> 32) | 1;
+    (0..probes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(delta)) % bits as u64) as usize)
+}
@@ -1,22 +1,30 @@
-use log::{error, info};
-use std::sync::{Arc, mpsc};
+use anyhow::Result;
+use log::info;
+use std::sync::{Arc, Weak, mpsc};
 
 use crate::{
-    common::next_log_file_name, engine::Engine, memtable::MemTable, sstable::SSTable,
-    writer::Writer,
+    common::next_log_file_name, compact::CompactMessage, engine::Engine, memtable::MemTable,
+    sstable::SSTable, writer::Writer,
 };
 
+pub enum FlushMessage {
+    Memtable(Arc<dyn MemTable>), // an immutable memtable installed in the version
+    Resume(mpsc::Sender<Result<(), String>>), // sent by `Engine::resume`, retry every immutable memtable
+    Wait(mpsc::Sender<Result<(), String>>), // answered once the memtables queued before are flushed
+    Shutdown,                               // sent by `Engine::close` after the last memtable
+}
+
 pub struct Flush {
-    engine: Arc<Engine>,
-    rx: mpsc::Receiver<Arc<MemTable>>,
-    compact_tx: mpsc::Sender<bool>,
+    engine: Weak<Engine>, // doesn't keep the engine alive, so it can be dropped
+    rx: mpsc::Receiver<FlushMessage>,
+    compact_tx: mpsc::Sender<CompactMessage>,
 }
 
 impl Flush {
     pub fn new(
-        engine: Arc<Engine>,
-        rx: mpsc::Receiver<Arc<MemTable>>,
-        compact_tx: mpsc::Sender<bool>,
+        engine: Weak<Engine>,
+        rx: mpsc::Receiver<FlushMessage>,
+        compact_tx: mpsc::Sender<CompactMessage>,
     ) -> Self {
         Self {
             rx,
@@ -28,60 +36,100 @@ impl Flush {
     pub fn start_loop(&self) {
         info!("flush thread started");
         loop {
-            let memtable: Arc<MemTable> = self.rx.recv().unwrap();
-
-            let filename = next_log_file_name(&self.engine.sstables_dir);
-            if let Err(err) = Writer::write(memtable.into_iter(), &filename) {
-                error!("error when flushing memtable: {:?}", err);
-                continue;
-            }
-            info!("flushed memtable to sstable file: {}", filename);
-            match SSTable::new(&filename) {
-                Err(err) => {
-                    error!(
-                        "error when create sstable from file: {:?}, {:?}",
-                        filename, err
-                    )
+            let msg = match self.rx.recv() {
+                Ok(FlushMessage::Shutdown) | Err(_) => break,
+                Ok(msg) => msg,
+            };
+            // the engine is being dropped, it flushes the remaining memtables itself
+            let Some(engine) = self.engine.upgrade() else {
+                break;
+            };
+            match msg {
+                FlushMessage::Memtable(memtable) => {
+                    // memtables are kept in order after an error, `resume` flushes them
+                    if engine.background_error().is_some() {
+                        continue;
+                    }
+                    if let Err(err) = Self::flush_memtable(&engine, memtable) {
+                        engine.set_background_error(format!("flush failed: {:#}", err));
+                        continue;
+                    }
+                }
+                FlushMessage::Resume(tx) => {
+                    // cleared before compactions are triggered, the error stays if the retry fails
+                    let res = Self::flush_all(&engine).map_err(|err| format!("{:#}", err));
+                    match &res {
+                        Ok(_) => engine.clear_background_error(),
+                        Err(err) => engine.set_background_error(format!("flush failed: {}", err)),
+                    }
+                    let _ = tx.send(res);
                 }
-                Ok(sstable) => {
-                    self.install_new_version(&memtable, sstable);
-                    info!("new version installed after flushing");
-                    let _ = self.compact_tx.send(true);
-                    info!("trigger message sent to compact thread");
+                FlushMessage::Wait(tx) => {
+                    let _ = tx.send(engine.background_error().map_or(Ok(()), Err));
+                    continue;
                 }
+                FlushMessage::Shutdown => break,
             }
+            let _ = self.compact_tx.send(CompactMessage::Trigger);
+            info!("trigger message sent to compact thread");
         }
+        info!("flush thread stopped");
     }
 
-    fn install_new_version(&self, memtable: &MemTable, sstable: SSTable) {
+    /// flush every immutable memtable of the current version, oldest first
+    pub fn flush_all(engine: &Engine) -> Result<()> {
+        let imm_memtables = engine.version.read().unwrap().imm_memtables.clone();
+        for memtable in imm_memtables {
+            Self::flush_memtable(engine, memtable)?;
+        }
+        Ok(())
+    }
+
+    /// write an immutable memtable to a new level 0 sstable and remove it from the version
+    pub fn flush_memtable(engine: &Engine, memtable: Arc<dyn MemTable>) -> Result<()> {
+        let filename = next_log_file_name(&engine.sstables_dir);
+        Writer::write(
+            memtable.iter(),
+            &filename,
+            &engine.options,
+            engine
+                .options
+                .rate_limiter
+                .clone()
+                .filter(|_| engine.options.rate_limit_flush),
+        )?;
+        info!("flushed memtable to sstable file: {}", filename);
+        let sstable = engine.open_sstable(&filename)?;
+        // the memtable stays in the version if the metadata file can't be written
+        Self::install_new_version(engine, memtable.as_ref(), sstable)?;
+        info!("new version installed after flushing");
+        Ok(())
+    }
+
+    fn install_new_version(
+        engine: &Engine,
+        memtable: &dyn MemTable,
+        sstable: SSTable,
+    ) -> Result<()> {
         let sstable = Arc::new(sstable);
         loop {
-            // read version and release lock
-            let mut version_ptr = std::ptr::null();
-            let mut new_version = {
-                let version = self.engine.version.read().unwrap();
-                version_ptr = version.as_ref();
-                let version = Arc::clone(&version);
-                (*version).clone()
-            };
+            // read version and release lock, the version is kept alive until compared
+            let version = Arc::clone(&engine.version.read().unwrap());
+            let mut new_version = (*version).clone();
 
             // remove memtable from queue
             let index = new_version
                 .imm_memtables
                 .iter()
-                .position(|m| std::ptr::eq((*m).as_ref(), memtable))
+                .position(|m| std::ptr::addr_eq(m.as_ref(), memtable))
                 .unwrap();
             new_version.imm_memtables.remove(index);
 
-            // add sstable
-            new_version.sstables.push(sstable.clone());
+            // add sstable, flushed sstables always go to level 0
+            new_version.level_mut(0).push(sstable.clone());
 
-            if self
-                .engine
-                .install_new_version(version_ptr, Arc::new(new_version))
-                .is_ok()
-            {
-                break;
+            if engine.install_new_version(&version, Arc::new(new_version))? {
+                return Ok(());
             }
         }
     }
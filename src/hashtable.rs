@@ -0,0 +1,103 @@
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    mem,
+    sync::{
+        RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use crate::{memtable::MemTable, merge::KVEntry};
+
+const SHARDS: usize = 16;
+const ENTRY_BYTES: usize = mem::size_of::<(String, (String, bool))>(); // held by the map for each key
+
+/// hash map split into shards, each behind its own lock, point lookups are O(1),
+/// ranges and flushes collect and sort the entries
+#[derive(Debug)]
+pub struct HashTable {
+    shards: Vec<RwLock<HashMap<String, (String, bool)>>>, // key -> (value, deleted)
+    byte_size: AtomicUsize, // an overwritten value is freed, only the newest one is counted
+}
+
+impl HashTable {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            byte_size: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, (String, bool)>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn set(&self, key: &str, value: &str, deleted: bool) {
+        let old = self
+            .shard(key)
+            .write()
+            .unwrap()
+            .insert(key.to_string(), (value.to_string(), deleted));
+        match old {
+            Some((old, _)) => {
+                self.byte_size.fetch_add(value.len(), Ordering::Relaxed);
+                self.byte_size.fetch_sub(old.len(), Ordering::Relaxed);
+            }
+            None => {
+                self.byte_size
+                    .fetch_add(ENTRY_BYTES + key.len() + value.len(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    // entries matching the predicate sorted by key, each shard is locked in turn
+    fn sorted(&self, predicate: impl Fn(&str) -> bool) -> Vec<KVEntry> {
+        let mut entries: Vec<KVEntry> = vec![];
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            entries.extend(
+                shard
+                    .iter()
+                    .filter(|(k, _)| predicate(k))
+                    .map(|(k, (v, deleted))| (k.clone(), v.clone(), *deleted)),
+            );
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+impl Default for HashTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemTable for HashTable {
+    fn get(&self, key: &str) -> Option<(String, bool)> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &str, value: &str) {
+        self.set(key, value, false);
+    }
+
+    fn del(&self, key: &str) {
+        self.set(key, "", true);
+    }
+
+    fn byte_size(&self) -> usize {
+        self.byte_size.load(Ordering::Relaxed)
+    }
+
+    fn range<'a>(&'a self, start: &'a str, end: &'a str) -> Box<dyn Iterator<Item = KVEntry> + 'a> {
+        Box::new(self.sorted(|k| start <= k && k <= end).into_iter())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = KVEntry> + '_> {
+        Box::new(self.sorted(|_| true).into_iter())
+    }
+}
@@ -3,145 +3,147 @@ use std::ops::Range;
 use anyhow::{Result, bail};
 
 // Disk file layout:
-//  index block offset (INDEX_META_LEN) |
-//  data block offset |
-//  key length | key value | val length | val value ...
+//  data block | data block | ... | sparse index block | filter block | footer
+// data block: key length | key value | val length | val value ... | restart points | codec tag
+// blocks are written at their actual length, data blocks may be compressed
+// files are written in a single pass by `SstWriter`, the sparse index, filter and footer go last
+// the block size is an option, data blocks are closed once they reach it
 pub const LOG_FILE_EXT: &str = "log";
-pub const BLOCK_SIZE_BYTES: usize = 16 * 1024; // 16 KB
-pub const MEMTABLE_FLUSH_LIMIT: usize = 64 * 1024 * 1024; // 64 MB
-pub const SSTABLE_COMPACT_LIMIT: usize = 4;
-// pub const MEMTABLE_MAX_SIZE_BYTES: usize = 64 * 1024 * 1024; // 64 MB
-// pub const BLOCK_COUNT: usize = MEMTABLE_MAX_SIZE_BYTES / BLOCK_SIZE_BYTES; // 4096 blocks in one level 0 log file
-
-pub const INDEX_BLOCK_OFFSET_META_OFFSET: usize = 0; // start at 0 of a file
-pub const INDEX_BLOCK_OFFSET_META_OFFSET_BYTES: usize = 8; // u64
-pub const DATA_BLOCK_OFFSET_META_OFFSET: usize = 8; // start right after index block offset data
-pub const DATA_BLOCK_OFFSET_META_OFFSET_BYTES: usize = 8; // u64
-// the length of metadata at the start of a log file, must fit into one block
-pub const META_DATA_BYTE_LEN: usize =
-    INDEX_BLOCK_OFFSET_META_OFFSET_BYTES + DATA_BLOCK_OFFSET_META_OFFSET_BYTES;
-// pub const SPARSE_INDEX_START_OFFSET: usize = META_DATA_BYTE_LEN;
-
-// byte layout of a single pair of KV: [key_len] [val_len] [key] [val]
+pub const MAX_BLOCK_SIZE_BYTES: usize = 64 * 1024; // restart offsets are u16
+
+// footer at the end of a log file:
+// [index block offset] [index block length] [filter block offset] [filter block length] [magic]
+// the filter block is empty when the file was written without a filter policy
+pub const FOOTER_INDEX_OFFSET_BYTES: usize = 8; // u64
+pub const FOOTER_INDEX_LEN_BYTES: usize = 8; // u64
+pub const FOOTER_FILTER_OFFSET_BYTES: usize = 8; // u64
+pub const FOOTER_FILTER_LEN_BYTES: usize = 8; // u64
+pub const FOOTER_MAGIC_BYTES: usize = 8; // u64
+pub const FOOTER_BYTE_LEN: usize = FOOTER_INDEX_OFFSET_BYTES
+    + FOOTER_INDEX_LEN_BYTES
+    + FOOTER_FILTER_OFFSET_BYTES
+    + FOOTER_FILTER_LEN_BYTES
+    + FOOTER_MAGIC_BYTES;
+pub const FOOTER_MAGIC: u64 = 0x6d6f_7373_6462_0002; // "mossdb" + format version
+
+// byte layout of a single pair of KV: [shared_len] [key_len] [val_len] [deleted] [key] [val]
+// keys are prefix compressed: shared_len is the length of the prefix shared with the previous key,
+// only the remaining key_len bytes of the key are stored, shared_len is 0 at restart points
 // key_len_len defines the byte size of key_len, limit the maximum length of byte in key
 // val_len_len is similar
+pub const SHARED_LEN_BYTES: usize = 1; // shared prefix is never longer than a key
 pub const KEY_LEN_BYTES: usize = 1; // 5 bits, use 1 byte to store physically, 32 Byte max key size, around 4 billion unique keys allowed
 pub const VAL_LEN_BYTES: usize = 2; // 10 bits, use 2 bytes to store, 1 KB max value size, combined with key, if fully stored, max use ~4TB space
 pub const DELETED_FLAG_BYTES: usize = 1;
-pub const KV_META_BYTES: usize = KEY_LEN_BYTES + VAL_LEN_BYTES + DELETED_FLAG_BYTES;
+pub const KV_META_BYTES: usize =
+    SHARED_LEN_BYTES + KEY_LEN_BYTES + VAL_LEN_BYTES + DELETED_FLAG_BYTES;
 pub const MAX_KEY_LEN: usize = 32; // a key max 32 bytes, used to limit at runtime
 pub const MAX_VAL_LEN: usize = 1024; // a val max 1024 bytes, used to limit at runtime
 pub const MAX_KEY_VAL_ENTRY_BYTE_LEN: usize = KV_META_BYTES + MAX_KEY_LEN + MAX_VAL_LEN;
 
-// use u64 for the offset in the log
-// u64 has 8 bytes, but we use 32 bytes to store it
-// becuase we want the block size can be dividable by the entry size
-// -> easier implementation, 256 index entries in each block
-// a entry in sparse index is fixed to MAX_KEY_LEN + 32 bytes
-// A entry = [ key bytes + zeros | offset bytes (8 bytes) + 24 bytes zeros ]
-pub const SPARSE_INDEX_ENTRY_BYTE_LEN: usize = MAX_KEY_LEN + 32;
-pub const SPARSE_INDEX_COUNT_PER_BLOCK: usize = BLOCK_SIZE_BYTES / SPARSE_INDEX_ENTRY_BYTE_LEN;
-
-pub struct Layout {}
-
-impl Layout {
-    pub fn build(kvs: impl IntoIterator<Item = (String, String, bool)>) -> Result<Vec<Blocks>> {
-        // write data blocks
-        let mut data_blocks = Blocks::new();
-        let mut first_keys_of_blocks: Vec<String> = vec![];
-        let mut data = [0_u8; MAX_KEY_VAL_ENTRY_BYTE_LEN];
-        let mut kv_entry = KVEntryWriter::new(&mut data);
-        for (k, v, deleted) in kvs.into_iter() {
-            let size = kv_entry.populate_with_key_val(k.as_bytes(), v.as_bytes(), deleted)?;
-
-            let is_in_new_block = data_blocks.write(&kv_entry.data[0..size]);
-            if is_in_new_block {
-                first_keys_of_blocks.push(k.to_string());
-            }
-        }
+// trailer at the end of each data block: [restart offset] * restart count | restart count
+// every RESTART_INTERVAL-th entry of a block is a restart point, its offset inside the block is recorded
+// lookups binary search the restart points, then scan at most RESTART_INTERVAL entries
+pub const RESTART_INTERVAL: usize = 16;
+pub const RESTART_OFFSET_BYTES: usize = 2; // u16, enough for an offset inside a 64 KB block
+pub const RESTART_COUNT_BYTES: usize = 2; // u16
+
+// a entry in sparse index: [key_len] [key] [block offset] [block length]
+// offset and length are u64, length is the on disk length of the data block
+pub const SPARSE_INDEX_OFFSET_BYTES: usize = 8; // u64
+pub const SPARSE_INDEX_LEN_BYTES: usize = 8; // u64
+
+// view of a data block, splits the entries from the restart point trailer
+pub struct DataBlock<'a> {
+    data: &'a [u8],     // entries
+    restarts: &'a [u8], // restart offsets
+}
 
-        let data_block_count = data_blocks.inner.len() as u64;
-        let mut index_block_count = data_block_count / SPARSE_INDEX_COUNT_PER_BLOCK as u64;
-        if !data_block_count.is_multiple_of(SPARSE_INDEX_COUNT_PER_BLOCK as u64) {
-            index_block_count += 1;
+impl<'a> DataBlock<'a> {
+    // a block with malformed trailer is treated as empty
+    pub fn new(block: &'a [u8]) -> Self {
+        let empty = Self {
+            data: &[],
+            restarts: &[],
+        };
+        if block.len() < RESTART_COUNT_BYTES {
+            return empty;
         }
-        let meta_block_count = 1_u64;
-
-        // write index blocks
-        let mut index_blocks = Blocks::new();
-        let mut index_data = [0_u8; SPARSE_INDEX_ENTRY_BYTE_LEN];
-        for (i, start_key) in first_keys_of_blocks.iter().enumerate() {
-            let mut cur_idx = 0;
-
-            // write key
-            for &byte in start_key.as_bytes() {
-                index_data[cur_idx] = byte;
-                cur_idx += 1;
-            }
-
-            // jump to the start of offset
-            cur_idx = MAX_KEY_LEN;
-
-            // write offset, left aligned
-            let key_offset_in_log: u64 =
-                (meta_block_count + index_block_count + i as u64) * BLOCK_SIZE_BYTES as u64;
-            for byte in key_offset_in_log.to_le_bytes() {
-                index_data[cur_idx] = byte;
-                cur_idx += 1;
-            }
-
-            index_blocks.write(&index_data);
+        let count_start = block.len() - RESTART_COUNT_BYTES;
+        let mut count_bytes = [0_u8; RESTART_COUNT_BYTES];
+        count_bytes.copy_from_slice(&block[count_start..]);
+        let restarts_len = u16::from_le_bytes(count_bytes) as usize * RESTART_OFFSET_BYTES;
+        if restarts_len > count_start {
+            return empty;
+        }
+        let restarts_start = count_start - restarts_len;
+        Self {
+            data: &block[..restarts_start],
+            restarts: &block[restarts_start..count_start],
         }
+    }
 
-        // write meta data block
-        let mut meta_block = Blocks::new();
-        let mut meta_data = [0_u8; META_DATA_BYTE_LEN];
-        // write index block offset
-        let index_offset_in_log: [u8; INDEX_BLOCK_OFFSET_META_OFFSET_BYTES] =
-            (meta_block_count * BLOCK_SIZE_BYTES as u64).to_le_bytes();
-        meta_data[INDEX_BLOCK_OFFSET_META_OFFSET
-            ..(INDEX_BLOCK_OFFSET_META_OFFSET + INDEX_BLOCK_OFFSET_META_OFFSET_BYTES)]
-            .copy_from_slice(&index_offset_in_log[..]);
-        // write data block offset
-        let data_offset_in_log: [u8; DATA_BLOCK_OFFSET_META_OFFSET_BYTES] =
-            ((meta_block_count + index_block_count) * BLOCK_SIZE_BYTES as u64).to_le_bytes();
-        meta_data[DATA_BLOCK_OFFSET_META_OFFSET
-            ..(DATA_BLOCK_OFFSET_META_OFFSET + DATA_BLOCK_OFFSET_META_OFFSET_BYTES)]
-            .copy_from_slice(&data_offset_in_log[..]);
-        meta_block.write(&meta_data);
-
-        Ok(vec![meta_block, index_blocks, data_blocks])
+    pub fn iter(&self) -> KVBlockIter<'a> {
+        KVBlockIter::new(self.data)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct Block {
-    pub inner: [u8; BLOCK_SIZE_BYTES],
-}
+    fn restart_count(&self) -> usize {
+        self.restarts.len() / RESTART_OFFSET_BYTES
+    }
 
-impl Block {
-    pub fn new() -> Self {
-        Self {
-            inner: [0; BLOCK_SIZE_BYTES],
-        }
+    fn restart_offset(&self, idx: usize) -> usize {
+        let start = idx * RESTART_OFFSET_BYTES;
+        let mut offset_bytes = [0_u8; RESTART_OFFSET_BYTES];
+        offset_bytes.copy_from_slice(&self.restarts[start..(start + RESTART_OFFSET_BYTES)]);
+        u16::from_le_bytes(offset_bytes) as usize
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.len()
+    fn key_at_restart(&self, idx: usize) -> Option<&'a [u8]> {
+        let offset = self.restart_offset(idx);
+        if offset >= self.data.len() {
+            return None;
+        }
+        // no shared prefix at a restart point, the stored key is the full key
+        let (_, k, _, _, _) = KVEntryReader::new(&self.data[offset..]).retrive_kv()?;
+        Some(k)
     }
 
-    pub fn kv_iter(&self) -> KVBlockIter<'_> {
-        KVBlockIter {
-            block: self,
-            offset: 0,
+    /// binary search the restart points, then scan from the closest one
+    /// return (value, deleted)
+    pub fn get(&self, key: &str) -> Option<(String, bool)> {
+        // find the first restart point whose key is bigger than the target
+        let (mut left, mut right) = (0, self.restart_count());
+        while left < right {
+            let mid = left + (right - left) / 2;
+            match self.key_at_restart(mid) {
+                Some(k) if k <= key.as_bytes() => left = mid + 1,
+                _ => right = mid,
+            }
         }
+        // all keys in the block are bigger than the target
+        if left == 0 {
+            return None;
+        }
+
+        let start = self.restart_offset(left - 1);
+        for (k, v, deleted) in KVBlockIter::new(&self.data[start..]) {
+            match k.as_str().cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Some((v, deleted)),
+                std::cmp::Ordering::Greater => return None,
+            }
+        }
+        None
     }
 }
 
 // iterator for block storing KV data
+// works on any byte slice of one block, e.g. a cached block or a memory mapped region
+// must start at a restart point, keys are restored from the previous key and the stored suffix
 pub struct KVBlockIter<'a> {
-    block: &'a Block,
+    data: &'a [u8],
     offset: usize,
+    key: Vec<u8>, // previous key
 }
 
 impl<'a> Iterator for KVBlockIter<'a> {
@@ -158,58 +160,137 @@ impl<'a> Iterator for KVBlockIter<'a> {
 }
 
 impl<'a> KVBlockIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            key: vec![],
+        }
+    }
+
     pub fn get_next(&mut self) -> Option<(String, String, bool)> {
-        if self.offset >= self.block.len() {
+        if self.offset >= self.data.len() {
             return None;
         }
-        let kv_entry = KVEntryReader::new(&self.block.inner[self.offset..]);
-        let (k, v, deleted, lenght) = kv_entry.retrive_kv()?;
+        let kv_entry = KVEntryReader::new(&self.data[self.offset..]);
+        let (shared, k, v, deleted, lenght) = kv_entry.retrive_kv()?;
+        if shared > self.key.len() {
+            return None;
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(k);
         self.offset += lenght;
         Some((
-            String::from_utf8_lossy(k).to_string(),
+            String::from_utf8_lossy(&self.key).to_string(),
             String::from_utf8_lossy(v).to_string(),
             deleted,
         ))
     }
 }
 
-#[derive(Default)]
-pub struct Blocks {
-    pub inner: Vec<Block>,
-    current_block_idx: isize,
-    current_idx_in_block: usize,
+// data blocks with a restart point trailer at the end of each block
+// a block is closed once the next entry and the trailer would exceed the block size
+pub struct DataBlocks {
+    block_size: usize,
+    blocks: Vec<Vec<u8>>, // closed blocks
+    current: Vec<u8>,     // the block being written, without trailer
+    restarts: Vec<usize>, // restart offsets of the current block
+    entry_count: usize,   // entry count of the current block
+    last_key: Vec<u8>,    // previous key, the base of prefix compression
+    entry: [u8; MAX_KEY_VAL_ENTRY_BYTE_LEN],
 }
 
-impl Blocks {
-    pub fn new() -> Self {
+impl DataBlocks {
+    pub fn new(block_size: usize) -> Self {
         Self {
-            inner: vec![],
-            current_block_idx: -1,
-            current_idx_in_block: 0,
+            block_size,
+            blocks: vec![],
+            current: vec![],
+            restarts: vec![],
+            entry_count: 0,
+            last_key: vec![],
+            entry: [0; MAX_KEY_VAL_ENTRY_BYTE_LEN],
         }
     }
 
-    // if current block remaning capacity is big enough to put the data, write to it
-    // if not, create a new block and write from the start
-    // return value:
-    //  - true: this data is written to a new block
-    //  - false: no new block is allocated
-    pub fn write(&mut self, data: &[u8]) -> bool {
-        let mut new_block_created = false;
-        if self.inner.is_empty() || BLOCK_SIZE_BYTES - self.current_idx_in_block < data.len() {
-            self.inner.push(Block::new());
-            self.current_block_idx += 1;
-            self.current_idx_in_block = 0;
-            new_block_created = true;
+    // add a kv entry, keys must be added in ascending order
+    // return true if the entry starts a new block
+    pub fn add(&mut self, key: &[u8], val: &[u8], deleted: bool) -> Result<bool> {
+        let mut is_restart = self.entry_count.is_multiple_of(RESTART_INTERVAL);
+        let mut size = self.encode(key, val, deleted, is_restart)?;
+
+        // the entry and the trailer, including a possible new restart point, must fit
+        let restart_count = self.restarts.len() + is_restart as usize;
+        let trailer_len = restart_count * RESTART_OFFSET_BYTES + RESTART_COUNT_BYTES;
+        let is_full = self.current.len() + size + trailer_len > self.block_size;
+        if self.entry_count > 0 && is_full {
+            self.finish_block();
+            // the first entry of a block is always a restart point
+            is_restart = true;
+            size = self.encode(key, val, deleted, is_restart)?;
         }
-        for &byte in data {
-            self.inner[self.current_block_idx as usize].inner[self.current_idx_in_block] = byte;
-            self.current_idx_in_block += 1;
+        let new_block_created = self.entry_count == 0;
+
+        if is_restart {
+            self.restarts.push(self.current.len());
         }
-        new_block_created
+        self.current.extend_from_slice(&self.entry[0..size]);
+        self.entry_count += 1;
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        Ok(new_block_created)
+    }
+
+    // encode the entry into the entry buffer, return the encoded size
+    fn encode(&mut self, key: &[u8], val: &[u8], deleted: bool, is_restart: bool) -> Result<usize> {
+        let shared = if is_restart {
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        KVEntryWriter::new(&mut self.entry).populate_with_key_val(shared, key, val, deleted)
+    }
+
+    // take the closed raw blocks, in order
+    pub fn take_finished(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.blocks)
+    }
+
+    // byte size of the block being written
+    pub fn current_len(&self) -> usize {
+        self.current.len()
+    }
+
+    // return all remaining raw blocks, in order
+    pub fn finish(mut self) -> Vec<Vec<u8>> {
+        self.finish_block();
+        self.blocks
+    }
+
+    // append restart points to the current block and close it
+    fn finish_block(&mut self) {
+        if self.entry_count == 0 {
+            return;
+        }
+        for &restart in &self.restarts {
+            self.current
+                .extend_from_slice(&(restart as u16).to_le_bytes());
+        }
+        self.current
+            .extend_from_slice(&(self.restarts.len() as u16).to_le_bytes());
+        self.blocks.push(std::mem::take(&mut self.current));
+        self.restarts.clear();
+        self.entry_count = 0;
     }
 }
 
+// (shared len, unshared key suffix, value, deleted, lenght of entry)
+pub type RawKVEntry<'a> = (usize, &'a [u8], &'a [u8], bool, usize);
+
 pub struct KVEntryReader<'a> {
     pub data: &'a [u8],
 }
@@ -219,8 +300,13 @@ impl<'a> KVEntryReader<'a> {
         Self { data }
     }
 
+    fn key_len_offset() -> usize {
+        SHARED_LEN_BYTES
+    }
+
     fn val_len_range() -> Range<usize> {
-        KEY_LEN_BYTES..(KEY_LEN_BYTES + VAL_LEN_BYTES)
+        let val_len_offset = SHARED_LEN_BYTES + KEY_LEN_BYTES;
+        val_len_offset..(val_len_offset + VAL_LEN_BYTES)
     }
 
     fn key_range(key_len: usize) -> Range<usize> {
@@ -232,33 +318,39 @@ impl<'a> KVEntryReader<'a> {
         val_offset..(val_offset + val_len)
     }
 
-    // return Option<(key_len, val_len)>
+    // return Option<(shared_len, key_len, val_len, deleted)>
     // if none -> current data doesn't has enough lenght of data to be interpreted as meta
-    fn retrive_meta(&self) -> Option<(usize, usize, bool)> {
+    fn retrive_meta(&self) -> Option<(usize, usize, usize, bool)> {
         if KV_META_BYTES > self.data.len() {
             return None;
         }
-        let key_len = self.data[0] as usize;
+        let shared_len = self.data[0] as usize;
+        let key_len = self.data[Self::key_len_offset()] as usize;
         let mut val_len_bytes: [u8; VAL_LEN_BYTES] = [0; VAL_LEN_BYTES];
         val_len_bytes[..].copy_from_slice(&self.data[Self::val_len_range()]);
         let val_len = u16::from_le_bytes(val_len_bytes) as usize;
         let deleted: u8 = self.data[KV_META_BYTES - DELETED_FLAG_BYTES];
 
-        Some((key_len, val_len, deleted == 1))
+        Some((shared_len, key_len, val_len, deleted == 1))
     }
 
     // if none -> data is not valid as a kv entry
     // e.g. not long enough, possible passed in the empty space at the end of a block
-    /// return Option<key, value, deleted, lenght of entry>
-    pub fn retrive_kv(&'a self) -> Option<(&'a [u8], &'a [u8], bool, usize)> {
-        let (key_len, val_len, deleted) = self.retrive_meta()?;
+    pub fn retrive_kv(&self) -> Option<RawKVEntry<'a>> {
+        let (shared_len, key_len, val_len, deleted) = self.retrive_meta()?;
         if key_len + val_len + KV_META_BYTES > self.data.len() {
             return None;
         }
         let key = &self.data[Self::key_range(key_len)];
         let val = &self.data[Self::val_range(key_len, val_len)];
 
-        Some((key, val, deleted, key_len + val_len + KV_META_BYTES))
+        Some((
+            shared_len,
+            key,
+            val,
+            deleted,
+            key_len + val_len + KV_META_BYTES,
+        ))
     }
 }
 
@@ -271,8 +363,13 @@ impl<'a> KVEntryWriter<'a> {
         Self { data }
     }
 
+    fn key_len_offset() -> usize {
+        SHARED_LEN_BYTES
+    }
+
     fn val_len_range() -> Range<usize> {
-        KEY_LEN_BYTES..(KEY_LEN_BYTES + VAL_LEN_BYTES)
+        let val_len_offset = SHARED_LEN_BYTES + KEY_LEN_BYTES;
+        val_len_offset..(val_len_offset + VAL_LEN_BYTES)
     }
 
     fn key_range(key_len: usize) -> Range<usize> {
@@ -284,9 +381,11 @@ impl<'a> KVEntryWriter<'a> {
         val_offset..(val_offset + val_len)
     }
 
+    // only the key bytes after the shared prefix are stored
     // return the populated size
     pub fn populate_with_key_val(
         &mut self,
+        shared: usize,
         key: &[u8],
         val: &[u8],
         deleted: bool,
@@ -297,9 +396,12 @@ impl<'a> KVEntryWriter<'a> {
         if val.len() > MAX_VAL_LEN {
             bail!("val too long");
         }
+        let key = &key[shared..];
 
+        // populate shared len
+        self.data[0] = shared as u8;
         // populate key len
-        self.data[0] = key.len() as u8;
+        self.data[Self::key_len_offset()] = key.len() as u8;
         // populate key
         self.data[Self::key_range(key.len())].copy_from_slice(key);
         // populate val len
@@ -318,64 +420,78 @@ impl<'a> KVEntryWriter<'a> {
     }
 }
 
-pub struct MetaData<'a> {
-    pub data: &'a mut [u8],
-}
+pub struct SparseIndexBlock {}
 
-impl<'a> MetaData<'a> {
-    pub fn new(data: &'a mut [u8]) -> Self {
-        Self { data }
-    }
-
-    pub fn retrieve_sparse_index_block_start_offset(&self) -> u64 {
-        let offset_data = &self.data[INDEX_BLOCK_OFFSET_META_OFFSET
-            ..(INDEX_BLOCK_OFFSET_META_OFFSET + INDEX_BLOCK_OFFSET_META_OFFSET_BYTES)];
-        let mut index_offset_bytes = [0_u8; INDEX_BLOCK_OFFSET_META_OFFSET_BYTES];
-        index_offset_bytes.copy_from_slice(offset_data);
-        u64::from_le_bytes(index_offset_bytes)
+impl SparseIndexBlock {
+    pub fn encode(index: &[(String, u64, u64)]) -> Vec<u8> {
+        let mut data = vec![];
+        for (key, offset, len) in index {
+            data.push(key.len() as u8);
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&len.to_le_bytes());
+        }
+        data
     }
 
-    pub fn retrieve_data_block_start_offset(&self) -> u64 {
-        let data_offset_data = &self.data[DATA_BLOCK_OFFSET_META_OFFSET
-            ..(DATA_BLOCK_OFFSET_META_OFFSET + DATA_BLOCK_OFFSET_META_OFFSET_BYTES)];
-        let mut data_offset_bytes = [0_u8; DATA_BLOCK_OFFSET_META_OFFSET_BYTES];
-        data_offset_bytes.copy_from_slice(data_offset_data);
-        u64::from_le_bytes(data_offset_bytes)
+    // return Vec<(first key, block offset, block length)>
+    pub fn decode(data: &[u8]) -> Result<Vec<(String, u64, u64)>> {
+        let mut res = vec![];
+        let mut cur = 0;
+        while cur < data.len() {
+            let key_len = data[cur] as usize;
+            let key_start = cur + KEY_LEN_BYTES;
+            let offset_start = key_start + key_len;
+            let len_start = offset_start + SPARSE_INDEX_OFFSET_BYTES;
+            let end = len_start + SPARSE_INDEX_LEN_BYTES;
+            if end > data.len() {
+                bail!("truncated sparse index entry at {}", cur);
+            }
+            let key = String::from_utf8_lossy(&data[key_start..offset_start]).to_string();
+            let offset = read_u64(&data[offset_start..len_start]);
+            let len = read_u64(&data[len_start..end]);
+            res.push((key, offset, len));
+            cur = end;
+        }
+        Ok(res)
     }
 }
 
-pub struct SparseIndexEntry<'a> {
-    data: &'a mut [u8],
+// (offset, length) of the sparse index block and the filter block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub index: (u64, u64),
+    pub filter: (u64, u64),
 }
 
-impl<'a> SparseIndexEntry<'a> {
-    pub fn new(data: &'a mut [u8]) -> Self {
-        Self { data }
+impl Footer {
+    pub fn encode(&self) -> [u8; FOOTER_BYTE_LEN] {
+        let mut data = [0_u8; FOOTER_BYTE_LEN];
+        let fields = [self.index.0, self.index.1, self.filter.0, self.filter.1];
+        for (i, field) in fields.iter().enumerate() {
+            data[(i * 8)..(i * 8 + 8)].copy_from_slice(&field.to_le_bytes());
+        }
+        data[(FOOTER_BYTE_LEN - FOOTER_MAGIC_BYTES)..].copy_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        data
     }
 
-    // key may not exist because key len is zero
-    pub fn retrieve_key(&self) -> Option<String> {
-        // a key max 32 byte, in sparse index, even a key is smaller than 32, extra space is filled with \0
-        // we need to retrive the true key
-        let mut key_end = 0;
-        for &byte in &self.data[0..MAX_KEY_LEN] {
-            if byte == b'\0' {
-                break;
-            }
-            key_end += 1;
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() != FOOTER_BYTE_LEN {
+            bail!("invalid footer length {}", data.len());
         }
-        if key_end == 0 {
-            return None;
+        if read_u64(&data[(FOOTER_BYTE_LEN - FOOTER_MAGIC_BYTES)..]) != FOOTER_MAGIC {
+            bail!("invalid footer magic, not a mossdb log file of the current format");
         }
-        Some(String::from_utf8_lossy(&self.data[0..key_end]).to_string())
+        let field = |i: usize| read_u64(&data[(i * 8)..(i * 8 + 8)]);
+        Ok(Self {
+            index: (field(0), field(1)),
+            filter: (field(2), field(3)),
+        })
     }
+}
 
-    pub fn retrieve_offset(&self) -> u64 {
-        // a value is 8 byte, a u64, but in sparse key index, it occupies 32 bytes, right padding with 0
-        // so we only get the first 8 bytes
-        let offset_data = &self.data[MAX_KEY_LEN..(MAX_KEY_LEN + 8)];
-        let mut offset_bytes = [0_u8; 8];
-        offset_bytes.copy_from_slice(offset_data);
-        u64::from_le_bytes(offset_bytes)
-    }
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(data);
+    u64::from_le_bytes(bytes)
 }
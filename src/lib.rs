@@ -1,12 +1,24 @@
+mod cache;
+mod catchup;
 pub mod common;
 mod compact;
+pub mod compaction;
+mod compression;
 pub mod engine;
+mod filter;
 mod flush;
+mod hashtable;
 mod layout;
 mod memtable;
+mod merge;
+pub mod options;
+pub mod ratelimiter;
 mod reader;
 pub mod repl;
+mod skiplist;
 mod sparseindex;
-mod sstable;
-mod versionset;
+pub mod sstable;
+pub mod statistics;
+mod vectortable;
+pub mod versionset;
 mod writer;
@@ -1,69 +1,35 @@
-use std::collections::{BTreeMap, btree_map};
+use std::{fmt::Debug, sync::Arc};
 
-#[derive(Debug)]
-pub struct MemTable {
-    store: BTreeMap<String, (String, bool)>, // key, (value, deleted)
-    byte_size: usize,
-}
-
-impl MemTable {
-    pub fn new() -> Self {
-        Self {
-            store: BTreeMap::new(),
-            byte_size: 0,
-        }
-    }
+use crate::{
+    hashtable::HashTable, merge::KVEntry, options::MemTableKind, skiplist::SkipList,
+    vectortable::VectorTable,
+};
 
+/// in-memory write buffer, shared by concurrent readers and writers,
+/// flushed to an sstable once it crosses `memtable_flush_limit`
+pub trait MemTable: Debug + Send + Sync {
     /// return (value, deleted)
-    pub fn get(&self, key: &str) -> Option<(String, bool)> {
-        let (val, deleted) = self.store.get(key)?;
-        if *deleted {
-            return Some(("".to_string(), true));
-        }
-        Some((val.to_string(), false))
-    }
-
-    pub fn put(&mut self, key: String, value: String) {
-        self.set(key, value, false);
-    }
+    fn get(&self, key: &str) -> Option<(String, bool)>;
 
-    fn set(&mut self, key: String, value: String, deleted: bool) {
-        let key_len = key.len();
-        let val_len = value.len();
-        match self.store.insert(key, (value, deleted)) {
-            None => {
-                self.byte_size += key_len;
-                self.byte_size += val_len;
-            }
-            Some(old) => {
-                self.byte_size -= old.0.len();
-                self.byte_size += val_len;
-            }
-        }
-    }
-
-    pub fn del(&mut self, key: String) {
-        self.set(key, "".to_string(), true);
-    }
+    fn put(&self, key: &str, value: &str);
 
-    pub fn byte_size(&self) -> usize {
-        self.byte_size
-    }
-}
+    fn del(&self, key: &str);
 
-impl<'a> IntoIterator for &'a MemTable {
-    type Item = (String, String, bool);
+    /// bytes of memory held for the entries, per entry overhead included,
+    /// overwritten values count as long as the memtable keeps them
+    fn byte_size(&self) -> usize;
 
-    type IntoIter = std::iter::Map<
-        btree_map::Iter<'a, String, (String, bool)>,
-        fn((&'a String, &'a (String, bool))) -> (String, String, bool),
-    >;
+    /// entries with keys in [start, end], sorted by key, one entry per key
+    fn range<'a>(&'a self, start: &'a str, end: &'a str) -> Box<dyn Iterator<Item = KVEntry> + 'a>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        fn clone_pair((k, v): (&String, &(String, bool))) -> (String, String, bool) {
-            (k.clone(), v.0.clone(), v.1)
-        }
+    /// all entries sorted by key, one entry per key
+    fn iter(&self) -> Box<dyn Iterator<Item = KVEntry> + '_>;
+}
 
-        self.store.iter().map(clone_pair)
+pub fn new_memtable(kind: MemTableKind) -> Arc<dyn MemTable> {
+    match kind {
+        MemTableKind::SkipList => Arc::new(SkipList::new()),
+        MemTableKind::Hash => Arc::new(HashTable::new()),
+        MemTableKind::Vector => Arc::new(VectorTable::new()),
     }
 }
@@ -0,0 +1,87 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+pub type KVEntry = (String, String, bool); // key, value, deleted
+
+// the current entry of a source, ordered for the max heap:
+// the smallest key on top, the newest source first on equal keys
+struct HeapItem {
+    entry: KVEntry,
+    source: usize,
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .entry
+            .0
+            .cmp(&self.entry.0)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
+
+/// k-way merge of sorted sources, O(log k) per entry
+/// only the newest entry of a key is returned, the newest source should be at the start
+pub struct MergeIterator<I> {
+    sources: Vec<I>,
+    heap: BinaryHeap<HeapItem>,
+    prev: Option<String>, // previous outputed key, used to skip value that should be discarded
+    drop_tombstones: bool,
+}
+
+impl<I: Iterator<Item = KVEntry>> MergeIterator<I> {
+    pub fn new(mut sources: Vec<I>, drop_tombstones: bool) -> Self {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some(entry) = iter.next() {
+                heap.push(HeapItem { entry, source });
+            }
+        }
+        Self {
+            sources,
+            heap,
+            prev: None,
+            drop_tombstones,
+        }
+    }
+}
+
+impl<I: Iterator<Item = KVEntry>> Iterator for MergeIterator<I> {
+    type Item = KVEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let HeapItem { entry, source } = self.heap.pop()?;
+            if let Some(next) = self.sources[source].next() {
+                self.heap.push(HeapItem {
+                    entry: next,
+                    source,
+                });
+            }
+
+            // an older value of the previous key
+            if self.prev.as_deref() == Some(entry.0.as_str()) {
+                continue;
+            }
+            self.prev = Some(entry.0.clone());
+
+            if entry.2 && self.drop_tombstones {
+                continue;
+            }
+            return Some(entry);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::common::MossError;
+use crate::compaction::{AdjacentPairs, CompactionFilter, CompactionStrategy};
+use crate::layout::{MAX_BLOCK_SIZE_BYTES, MAX_KEY_VAL_ENTRY_BYTE_LEN};
+use crate::ratelimiter::RateLimiter;
+
+pub const DEFAULT_MEMTABLE_FLUSH_LIMIT: usize = 64 * 1024 * 1024; // 64 MB
+pub const DEFAULT_SSTABLE_COMPACT_LIMIT: usize = 4;
+pub const DEFAULT_BLOCK_SIZE: usize = 16 * 1024; // 16 KB, upper bound of a raw data block
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+pub const DEFAULT_CATCH_UP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how sstable files are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// read blocks with file io into a per sstable cached block
+    #[default]
+    Buffered,
+    /// mmap each sstable file and serve blocks directly from the mapped memory,
+    /// zero copy, relies on the OS page cache, suits read heavy workloads with plenty of RAM
+    Mmap,
+}
+
+/// codec used to compress data blocks of newly written sstables
+/// each block records its own codec, so files written with different settings can be mixed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+}
+
+/// in-memory write buffer implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemTableKind {
+    /// concurrent sorted skiplist, good all-round choice, cheap scans
+    #[default]
+    SkipList,
+    /// sharded hash map, fastest point lookups, scans and flushes sort the entries
+    Hash,
+    /// unsorted append-only vector sorted at flush time, fastest writes for bulk loads,
+    /// lookups scan the whole memtable
+    Vector,
+}
+
+/// filter built for each newly written sstable, lets lookups skip sstables without the key
+/// each file records its own filter, so files written with different settings can be mixed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterPolicy {
+    #[default]
+    None,
+    /// bloom filter over all keys of the file, 10 bits per key give about 1% false positives
+    Bloom { bits_per_key: usize },
+}
+
+/// tunables of an engine, fixed when the engine is opened, validated by `Engine::open`
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub create_if_missing: bool, // create the directory and the database if they don't exist
+    pub error_if_exists: bool,   // fail to open an existing database
+    pub block_size: usize,       // raw data blocks are closed at this many bytes
+    pub block_cache_size: usize, // bytes of decompressed blocks cached for `ReadMode::Buffered`, 0 disables the cache
+    pub filter_policy: FilterPolicy,
+    pub memtable_flush_limit: usize, // trigger flush when the memory held by the memtable crosses this many bytes
+    pub sstable_compact_limit: usize, // trigger compact above the limit, will keep files under this number, level 0 only for `Leveled`
+    pub memtable: MemTableKind,
+    pub read_mode: ReadMode,
+    pub compression: Compression,
+    pub target_file_size: Option<u64>, // compaction output below level 0 rolls over to a new file at this many bytes, one file if None, `Leveled` only
+    pub compaction_strategy: Arc<dyn CompactionStrategy>, // picks sstables to compact, `AdjacentPairs` by default
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>, // drops or rewrites entries during compaction
+    pub rate_limiter: Option<Arc<RateLimiter>>, // limits compaction writes, keep the Arc to adjust the rate at runtime
+    pub rate_limit_flush: bool,                 // apply the rate limiter to flushes too
+    pub max_imm_memtables: usize, // writes stop at this many memtables waiting for flush, slow down one before
+    pub level0_slowdown_files: usize, // writes slow down at this many level 0 sstables
+    pub level0_stop_files: usize, // writes stop at this many level 0 sstables
+    pub max_background_compactions: usize, // compaction threads, jobs never share an input sstable
+    pub max_subcompactions: usize, // threads splitting one compaction below level 0 by key range
+    pub catch_up_interval: Duration, // a secondary instance re-reads the primary's metadata file this often
+    pub quarantine_orphan_files: bool, // move orphaned files found on open into a subdirectory instead of removing them
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            error_if_exists: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            filter_policy: FilterPolicy::default(),
+            memtable_flush_limit: DEFAULT_MEMTABLE_FLUSH_LIMIT,
+            sstable_compact_limit: DEFAULT_SSTABLE_COMPACT_LIMIT,
+            memtable: MemTableKind::default(),
+            read_mode: ReadMode::default(),
+            compression: Compression::default(),
+            target_file_size: None,
+            compaction_strategy: Arc::new(AdjacentPairs),
+            compaction_filter: None,
+            rate_limiter: None,
+            rate_limit_flush: false,
+            max_imm_memtables: 4,
+            level0_slowdown_files: 20,
+            level0_stop_files: 36,
+            max_background_compactions: 1,
+            max_subcompactions: 1,
+            catch_up_interval: DEFAULT_CATCH_UP_INTERVAL,
+            quarantine_orphan_files: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    /// reject settings the engine can't work with
+    pub fn validate(&self) -> Result<(), MossError> {
+        let invalid = |msg: String| Err(MossError::InvalidArgument(msg));
+        if self.memtable_flush_limit == 0 {
+            return invalid("memtable_flush_limit must be positive".to_string());
+        }
+        if self.sstable_compact_limit == 0 {
+            return invalid("sstable_compact_limit must be positive".to_string());
+        }
+        // a block holds at least one entry of the biggest size
+        if self.block_size < MAX_KEY_VAL_ENTRY_BYTE_LEN || self.block_size > MAX_BLOCK_SIZE_BYTES {
+            return invalid(format!(
+                "block_size must be between {} and {} bytes, got {}",
+                MAX_KEY_VAL_ENTRY_BYTE_LEN, MAX_BLOCK_SIZE_BYTES, self.block_size
+            ));
+        }
+        if let FilterPolicy::Bloom { bits_per_key } = self.filter_policy
+            && !(1..=64).contains(&bits_per_key)
+        {
+            return invalid(format!(
+                "bloom filter bits_per_key must be between 1 and 64, got {}",
+                bits_per_key
+            ));
+        }
+        if self.target_file_size == Some(0) {
+            return invalid("target_file_size must be positive".to_string());
+        }
+        // level 0 strategies count files to bound the runs, split outputs would never reduce the count
+        if self.target_file_size.is_some() && self.compaction_strategy.num_levels() < 2 {
+            return invalid(format!(
+                "target_file_size needs a strategy with levels below level 0, got {:?}",
+                self.compaction_strategy
+            ));
+        }
+        if self.max_imm_memtables == 0 {
+            return invalid("max_imm_memtables must be positive".to_string());
+        }
+        if self.level0_slowdown_files > self.level0_stop_files {
+            return invalid(format!(
+                "level0_slowdown_files {} is above level0_stop_files {}",
+                self.level0_slowdown_files, self.level0_stop_files
+            ));
+        }
+        if self.max_background_compactions == 0 || self.max_subcompactions == 0 {
+            return invalid(
+                "max_background_compactions and max_subcompactions must be positive".to_string(),
+            );
+        }
+        if self.catch_up_interval.is_zero() {
+            return invalid("catch_up_interval must be positive".to_string());
+        }
+        self.compaction_strategy.validate()
+    }
+}
+
+/// builds `Options`, settings not given keep their default
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.options.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.options.error_if_exists = error_if_exists;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.options.block_size = block_size;
+        self
+    }
+
+    pub fn block_cache_size(mut self, block_cache_size: usize) -> Self {
+        self.options.block_cache_size = block_cache_size;
+        self
+    }
+
+    pub fn filter_policy(mut self, filter_policy: FilterPolicy) -> Self {
+        self.options.filter_policy = filter_policy;
+        self
+    }
+
+    pub fn memtable_flush_limit(mut self, memtable_flush_limit: usize) -> Self {
+        self.options.memtable_flush_limit = memtable_flush_limit;
+        self
+    }
+
+    pub fn sstable_compact_limit(mut self, sstable_compact_limit: usize) -> Self {
+        self.options.sstable_compact_limit = sstable_compact_limit;
+        self
+    }
+
+    pub fn memtable(mut self, memtable: MemTableKind) -> Self {
+        self.options.memtable = memtable;
+        self
+    }
+
+    pub fn read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.options.read_mode = read_mode;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn target_file_size(mut self, target_file_size: Option<u64>) -> Self {
+        self.options.target_file_size = target_file_size;
+        self
+    }
+
+    pub fn compaction_strategy(mut self, compaction_strategy: Arc<dyn CompactionStrategy>) -> Self {
+        self.options.compaction_strategy = compaction_strategy;
+        self
+    }
+
+    pub fn compaction_filter(mut self, compaction_filter: Arc<dyn CompactionFilter>) -> Self {
+        self.options.compaction_filter = Some(compaction_filter);
+        self
+    }
+
+    pub fn rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.options.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn rate_limit_flush(mut self, rate_limit_flush: bool) -> Self {
+        self.options.rate_limit_flush = rate_limit_flush;
+        self
+    }
+
+    pub fn max_imm_memtables(mut self, max_imm_memtables: usize) -> Self {
+        self.options.max_imm_memtables = max_imm_memtables;
+        self
+    }
+
+    pub fn level0_slowdown_files(mut self, level0_slowdown_files: usize) -> Self {
+        self.options.level0_slowdown_files = level0_slowdown_files;
+        self
+    }
+
+    pub fn level0_stop_files(mut self, level0_stop_files: usize) -> Self {
+        self.options.level0_stop_files = level0_stop_files;
+        self
+    }
+
+    pub fn max_background_compactions(mut self, max_background_compactions: usize) -> Self {
+        self.options.max_background_compactions = max_background_compactions;
+        self
+    }
+
+    pub fn max_subcompactions(mut self, max_subcompactions: usize) -> Self {
+        self.options.max_subcompactions = max_subcompactions;
+        self
+    }
+
+    pub fn catch_up_interval(mut self, catch_up_interval: Duration) -> Self {
+        self.options.catch_up_interval = catch_up_interval;
+        self
+    }
+
+    pub fn quarantine_orphan_files(mut self, quarantine_orphan_files: bool) -> Self {
+        self.options.quarantine_orphan_files = quarantine_orphan_files;
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
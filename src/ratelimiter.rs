@@ -0,0 +1,76 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// token bucket limiting background write throughput, shared by threads through an `Arc`
+/// the rate can be changed at runtime, 0 means unlimited
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: u64,
+    available: f64, // tokens, negative while writers are in debt
+    last_refill: Instant,
+}
+
+impl Bucket {
+    // burst of at most 100ms worth of writes, keeps the rate smooth
+    fn capacity(&self) -> f64 {
+        self.bytes_per_sec as f64 / 10.0
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available =
+            (self.available + elapsed * self.bytes_per_sec as f64).min(self.capacity());
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let mut bucket = Bucket {
+            bytes_per_sec,
+            available: 0.0,
+            last_refill: Instant::now(),
+        };
+        bucket.available = bucket.capacity();
+        Self {
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.bytes_per_sec = bytes_per_sec;
+        bucket.available = bucket.available.min(bucket.capacity());
+    }
+
+    /// block until `bytes` may be written, requests larger than the burst are paid off by sleeping
+    pub fn request(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.bytes_per_sec == 0 {
+                return;
+            }
+            bucket.refill();
+            bucket.available -= bytes as f64;
+            if bucket.available >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.available / bucket.bytes_per_sec as f64)
+        };
+        thread::sleep(wait);
+    }
+}
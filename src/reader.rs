@@ -1,95 +1,111 @@
 use anyhow::{Context, Result, bail};
+use memmap2::Mmap;
+use std::borrow::Cow;
 use std::fmt;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom};
-use std::os::unix::fs::MetadataExt;
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::Arc;
 
-use crate::layout::{
-    BLOCK_SIZE_BYTES, Block, KVBlockIter, MetaData, SPARSE_INDEX_COUNT_PER_BLOCK,
-    SPARSE_INDEX_ENTRY_BYTE_LEN, SparseIndexEntry,
-};
+use crate::cache::BlockCache;
+use crate::compression::decompress_block;
+use crate::filter::BloomFilter;
+use crate::layout::{DataBlock, FOOTER_BYTE_LEN, Footer, KVBlockIter, SparseIndexBlock};
+
+// (sparse index entries, filter), read from the end of a log file
+pub type TableMeta = (Vec<(String, u64, u64)>, Option<BloomFilter>);
 
 pub struct CachedReader {
-    cached_block: Block,
-    has_data_in_cache: bool, // TODO: rmv flag, use some type safe way, unsafe may needed
+    cached_block: Arc<Vec<u8>>, // decompressed, may be shared with the block cache
+    read_buf: Vec<u8>,          // on disk form of the last read block
+    has_data_in_cache: bool,    // TODO: rmv flag, use some type safe way, unsafe may needed
     block_offset: u64,
     filename: String,
+    file: Arc<File>, // the handle of the sstable, read at positions so iterators share it
+    block_cache: Option<Arc<BlockCache>>, // shared by all sstables of an engine
 }
 
 impl CachedReader {
-    pub fn new(filename: String) -> Self {
-        Self {
-            cached_block: Block::new(),
+    pub fn new(
+        filename: String,
+        file: Arc<File>,
+        block_cache: Option<Arc<BlockCache>>,
+    ) -> Result<Self> {
+        Ok(Self {
+            cached_block: Arc::new(vec![]),
+            read_buf: vec![],
             has_data_in_cache: false,
             block_offset: 0,
             filename,
-        }
+            file,
+            block_cache,
+        })
     }
 
-    pub fn kv_block_iter(&mut self, block_offset: u64) -> Result<KVBlockIter<'_>> {
+    pub fn kv_block_iter(&mut self, block_offset: u64, block_len: u64) -> Result<KVBlockIter<'_>> {
+        Ok(self.cached_data_block(block_offset, block_len)?.iter())
+    }
+
+    fn cached_data_block(&mut self, block_offset: u64, block_len: u64) -> Result<DataBlock<'_>> {
         if !self.has_data_in_cache || self.block_offset != block_offset {
-            self.load_block_to_cache(block_offset)?;
+            self.load_data_block_to_cache(block_offset, block_len)?;
             self.block_offset = block_offset;
         }
-        Ok(self.cached_block.kv_iter())
+        Ok(DataBlock::new(&self.cached_block))
     }
 
     pub fn get_file_size(&self) -> Result<u64> {
-        let file = OpenOptions::new().read(true).open(&self.filename)?;
-        Ok(file.metadata()?.size())
+        Ok(self.file.metadata()?.size())
     }
 
-    // (value, deleted)
-    pub fn read_key(&mut self, block_offset: u64, key: &str) -> Result<(String, bool)> {
-        for (k, v, deleted) in self.kv_block_iter(block_offset)? {
-            if k == key {
-                return Ok((v, deleted));
-            }
-        }
-
-        // TODO: add a jump array to block to accelerate search speed in one block
-        bail!("key not found in current block");
+    // (value, deleted), None if the block doesn't hold the key
+    pub fn read_key(
+        &mut self,
+        block_offset: u64,
+        block_len: u64,
+        key: &str,
+    ) -> Result<Option<(String, bool)>> {
+        Ok(self.cached_data_block(block_offset, block_len)?.get(key))
     }
 
-    pub fn read_sparse_index(&mut self) -> Result<Vec<(String, u64)>> {
-        if !self.has_data_in_cache || self.block_offset != 0 {
-            self.load_block_to_cache(0)?;
-            self.block_offset = 0;
-        }
-        let meta = MetaData::new(&mut self.cached_block.inner);
-        let mut cur_offset = meta.retrieve_sparse_index_block_start_offset();
-        let data_block_start_offset = meta.retrieve_data_block_start_offset();
-        let mut res: Vec<(String, u64)> = vec![];
-        let mut has_more_data = true;
-        while cur_offset < data_block_start_offset && has_more_data {
-            if !self.has_data_in_cache || self.block_offset != cur_offset {
-                self.load_block_to_cache(cur_offset)?;
-                self.block_offset = cur_offset;
-            }
-
-            for i in 0..SPARSE_INDEX_COUNT_PER_BLOCK {
-                let sparse_index_entry = SparseIndexEntry::new(
-                    &mut self.cached_block.inner[(i * SPARSE_INDEX_ENTRY_BYTE_LEN)..],
-                );
-                let Some(key) = sparse_index_entry.retrieve_key() else {
-                    has_more_data = false;
-                    break;
-                };
-                let offset = sparse_index_entry.retrieve_offset();
-                res.push((key, offset));
-            }
-
-            cur_offset += BLOCK_SIZE_BYTES as u64;
+    pub fn read_meta(&mut self) -> Result<TableMeta> {
+        let file_size = self.get_file_size()?;
+        if file_size < FOOTER_BYTE_LEN as u64 {
+            bail!("file {} is too small to be a log file", self.filename);
         }
-
-        Ok(res)
+        self.read_to_buf(file_size - FOOTER_BYTE_LEN as u64, FOOTER_BYTE_LEN as u64)?;
+        let footer = Footer::decode(&self.read_buf)?;
+        self.read_to_buf(footer.filter.0, footer.filter.1)?;
+        let filter = BloomFilter::decode(&self.read_buf);
+        self.read_to_buf(footer.index.0, footer.index.1)?;
+        Ok((SparseIndexBlock::decode(&self.read_buf)?, filter))
     }
 
-    fn load_block_to_cache(&mut self, start: u64) -> Result<()> {
-        let mut file = OpenOptions::new().read(true).open(&self.filename)?;
-        file.seek(SeekFrom::Start(start))?;
-        file.read_exact(&mut self.cached_block.inner[..])
+    fn read_to_buf(&mut self, start: u64, len: u64) -> Result<()> {
+        self.read_buf.resize(len as usize, 0);
+        self.file
+            .read_exact_at(&mut self.read_buf, start)
             .context("failed to read block")?;
+        Ok(())
+    }
+
+    fn load_data_block_to_cache(&mut self, start: u64, len: u64) -> Result<()> {
+        // the cache is overwritten below
+        self.has_data_in_cache = false;
+        if let Some(block) = self
+            .block_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.filename, start))
+        {
+            self.cached_block = block;
+            self.has_data_in_cache = true;
+            return Ok(());
+        }
+        self.read_to_buf(start, len)?;
+        let raw = decompress_block(&self.read_buf)?;
+        self.cached_block = Arc::new(raw.into_owned());
+        if let Some(cache) = &self.block_cache {
+            cache.insert(&self.filename, start, Arc::clone(&self.cached_block));
+        }
         self.has_data_in_cache = true;
         Ok(())
     }
@@ -105,3 +121,73 @@ impl fmt::Debug for CachedReader {
             .finish()
     }
 }
+
+// a reader serving blocks directly from a memory mapped sstable file
+// no copy into a block buffer, the OS page cache does the caching
+pub struct MmapReader {
+    mmap: Mmap,
+    filename: String,
+}
+
+impl MmapReader {
+    // the mapping stays valid after `file` is closed
+    pub fn new(filename: String, file: &File) -> Result<Self> {
+        // safety: sstable files are immutable once written,
+        // and only removed after the last reference to the sstable is dropped
+        let mmap = unsafe { Mmap::map(file) }.context("failed to mmap sstable file")?;
+        Ok(Self { mmap, filename })
+    }
+
+    fn slice(&self, start: u64, len: u64) -> Result<&[u8]> {
+        let start = start as usize;
+        let end = start + len as usize;
+        if end > self.mmap.len() {
+            bail!(
+                "block at offset {} is out of range of file {}",
+                start,
+                self.filename
+            );
+        }
+        Ok(&self.mmap[start..end])
+    }
+
+    // raw data block, only compressed blocks are copied
+    pub fn data_block(&self, block_offset: u64, block_len: u64) -> Result<Cow<'_, [u8]>> {
+        decompress_block(self.slice(block_offset, block_len)?)
+    }
+
+    pub fn get_file_size(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    // (value, deleted), None if the block doesn't hold the key
+    pub fn read_key(
+        &self,
+        block_offset: u64,
+        block_len: u64,
+        key: &str,
+    ) -> Result<Option<(String, bool)>> {
+        Ok(DataBlock::new(&self.data_block(block_offset, block_len)?).get(key))
+    }
+
+    pub fn read_meta(&self) -> Result<TableMeta> {
+        let file_size = self.get_file_size();
+        if file_size < FOOTER_BYTE_LEN as u64 {
+            bail!("file {} is too small to be a log file", self.filename);
+        }
+        let footer = self.slice(file_size - FOOTER_BYTE_LEN as u64, FOOTER_BYTE_LEN as u64)?;
+        let footer = Footer::decode(footer)?;
+        let filter = BloomFilter::decode(self.slice(footer.filter.0, footer.filter.1)?);
+        let index = SparseIndexBlock::decode(self.slice(footer.index.0, footer.index.1)?)?;
+        Ok((index, filter))
+    }
+}
+
+impl fmt::Debug for MmapReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmapReader")
+            .field("mapped_len", &self.mmap.len())
+            .field("filename", &self.filename)
+            .finish()
+    }
+}
@@ -3,20 +3,23 @@ use std::{
     sync::Arc,
 };
 
-use crate::{
-    engine::Engine,
-    layout::{MEMTABLE_FLUSH_LIMIT, SSTABLE_COMPACT_LIMIT},
-};
+use crate::{engine::Engine, options::Options};
 use log::error;
 
 pub struct Repl {
     engine: Arc<Engine>,
 }
 
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Repl {
     pub fn new() -> Self {
         Self {
-            engine: Engine::new("./", MEMTABLE_FLUSH_LIMIT, SSTABLE_COMPACT_LIMIT).unwrap(),
+            engine: Engine::open("./", Options::default()).unwrap(),
         }
     }
 
@@ -27,7 +30,9 @@ impl Repl {
                     println!("expect a key and a value");
                     return;
                 }
-                self.engine.put(args[0], args[1]);
+                if let Err(err) = self.engine.put(args[0], args[1]) {
+                    println!("put failed: {}", err);
+                }
             }
             "get" => {
                 if args.len() != 1 {
@@ -45,10 +50,30 @@ impl Repl {
                     println!("expect a key");
                     return;
                 }
-                self.engine.del(args[0]);
+                if let Err(err) = self.engine.del(args[0]) {
+                    println!("del failed: {}", err);
+                }
+            }
+            "scan" => {
+                if args.len() != 2 {
+                    println!("expect a start key and an end key");
+                    return;
+                }
+                match self.engine.scan(args[0], args[1]) {
+                    Ok(kvs) => {
+                        for (k, v) in kvs {
+                            println!("{} = {}", k, v);
+                        }
+                    }
+                    Err(err) => println!("scan failed: {:?}", err),
+                }
             }
             "dump" => self.engine.dump(),
-            "flush" => self.engine.flush(),
+            "flush" => {
+                if let Err(err) = self.engine.flush() {
+                    println!("flush failed: {}", err);
+                }
+            }
             _ => {}
         }
     }
@@ -73,6 +98,13 @@ impl Repl {
                     error!("{}", e);
                     continue;
                 }
+                // end of input, flush everything before exiting
+                Ok(0) => {
+                    if let Err(e) = self.engine.close() {
+                        error!("{}", e);
+                    }
+                    return;
+                }
                 Ok(_) => {
                     let line: Vec<&str> = line.split_whitespace().collect();
                     self.process_line(&line);
@@ -0,0 +1,404 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    marker::PhantomData,
+    mem, ptr, slice, str,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{memtable::MemTable, merge::KVEntry};
+
+const MAX_HEIGHT: usize = 12;
+const BRANCHING: u64 = 4; // 1 in 4 nodes of a level also appears in the level above
+const ARENA_BLOCK_BYTES: usize = 64 * 1024;
+
+// bump allocator, everything allocated lives until the memtable is dropped
+// lock free, writers bump the offset of the current block with fetch_add,
+// the first writer finding it full swaps in a new block with compare and swap
+struct Arena {
+    current: AtomicPtr<ArenaBlock>, // older blocks are linked from it
+    allocated: AtomicUsize,         // bytes handed out, only grows, overwritten values stay counted
+}
+
+struct ArenaBlock {
+    data: *mut u64,    // u64 words, so every allocation is 8 bytes aligned
+    len: usize,        // bytes
+    used: AtomicUsize, // bytes bumped, passes `len` once the block is full
+    prev: *mut ArenaBlock,
+}
+
+impl ArenaBlock {
+    // `used` bytes taken by the caller at the start
+    fn new(len: usize, used: usize, prev: *mut ArenaBlock) -> *mut ArenaBlock {
+        let words = vec![0_u64; len / mem::size_of::<u64>()].into_boxed_slice();
+        Box::into_raw(Box::new(ArenaBlock {
+            data: Box::into_raw(words) as *mut u64,
+            len,
+            used: AtomicUsize::new(used),
+            prev,
+        }))
+    }
+}
+
+impl Drop for ArenaBlock {
+    fn drop(&mut self) {
+        let words = self.len / mem::size_of::<u64>();
+        // safety: allocated as a boxed slice of this many words in `ArenaBlock::new`
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, words)) });
+    }
+}
+
+impl Arena {
+    fn new() -> Self {
+        Self {
+            current: AtomicPtr::new(ptr::null_mut()),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let size = size.max(1).next_multiple_of(mem::size_of::<u64>());
+        self.allocated.fetch_add(size, Ordering::Relaxed);
+        loop {
+            let current = self.current.load(Ordering::Acquire);
+            // safety: blocks are only freed when the arena is dropped
+            if let Some(block) = unsafe { current.as_ref() } {
+                let offset = block.used.fetch_add(size, Ordering::Relaxed);
+                if offset + size <= block.len {
+                    // safety: [offset, offset + size) is inside the block and handed out once
+                    return unsafe { (block.data as *mut u8).add(offset) };
+                }
+            }
+            // full, the rest of the block is wasted
+            let block = ArenaBlock::new(size.max(ARENA_BLOCK_BYTES), size, current);
+            match self
+                .current
+                .compare_exchange(current, block, Ordering::AcqRel, Ordering::Acquire)
+            {
+                // safety: the new block starts with the `size` bytes reserved above
+                Ok(_) => return unsafe { (*block).data as *mut u8 },
+                // another writer swapped in a block first, bump that one
+                Err(_) => drop(unsafe { Box::from_raw(block) }),
+            }
+        }
+    }
+
+    fn alloc_bytes(&self, bytes: &[u8]) -> *const u8 {
+        let dst = self.alloc(bytes.len());
+        // safety: dst has room for bytes, the arena never hands out the same memory twice
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len()) };
+        dst
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let mut block = *self.current.get_mut();
+        while !block.is_null() {
+            // safety: every block was swapped in once, and is freed once here
+            let owned = unsafe { Box::from_raw(block) };
+            block = owned.prev;
+        }
+    }
+}
+
+struct Value {
+    data: *const u8,
+    len: usize,
+    deleted: bool,
+}
+
+impl Value {
+    fn as_str(&self) -> &str {
+        // safety: copied from a &str into the arena, never modified
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.data, self.len)) }
+    }
+}
+
+// a key with all its levels, the value is swapped when the key is written again
+struct Node {
+    key: *const u8,
+    key_len: usize,
+    value: AtomicPtr<Value>,
+    next: [AtomicPtr<Node>; MAX_HEIGHT],
+}
+
+impl Node {
+    fn key(&self) -> &[u8] {
+        // safety: copied into the arena, never modified
+        unsafe { slice::from_raw_parts(self.key, self.key_len) }
+    }
+
+    fn next(&self, level: usize) -> *mut Node {
+        self.next[level].load(Ordering::Acquire)
+    }
+}
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+fn random_height() -> usize {
+    RNG.with(|rng| {
+        let mut height = 1;
+        while height < MAX_HEIGHT {
+            // xorshift
+            let mut x = rng.get();
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            rng.set(x);
+            if !x.is_multiple_of(BRANCHING) {
+                break;
+            }
+            height += 1;
+        }
+        height
+    })
+}
+
+/// concurrent skiplist, nodes are allocated in an arena and never removed
+/// readers never block, writers link nodes with compare and swap
+pub struct SkipList {
+    arena: Arena,
+    head: *mut Node,
+    height: AtomicUsize, // levels in use
+    head_bytes: usize,   // arena bytes of the head node, an empty skiplist has a byte size of 0
+}
+
+// safety: nodes and values are immutable after being published with release ordering,
+// links and values only change through atomics, the arena lives as long as the memtable
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+// safety: the raw pointers only point to the blocks owned by the arena, and into them
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let arena = Arena::new();
+        let head = Self::alloc_node(&arena, b"", ptr::null_mut());
+        let head_bytes = arena.allocated();
+        Self {
+            arena,
+            head,
+            height: AtomicUsize::new(1),
+            head_bytes,
+        }
+    }
+
+    fn alloc_node(arena: &Arena, key: &[u8], value: *mut Value) -> *mut Node {
+        let node = arena.alloc(mem::size_of::<Node>()) as *mut Node;
+        let node_value = Node {
+            key: arena.alloc_bytes(key),
+            key_len: key.len(),
+            value: AtomicPtr::new(value),
+            next: [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HEIGHT],
+        };
+        // safety: fresh 8 bytes aligned memory of the size of a node
+        unsafe { ptr::write(node, node_value) };
+        node
+    }
+
+    fn alloc_value(&self, value: &str, deleted: bool) -> *mut Value {
+        let data = self.arena.alloc_bytes(value.as_bytes());
+        let res = self.arena.alloc(mem::size_of::<Value>()) as *mut Value;
+        // safety: fresh 8 bytes aligned memory of the size of a value
+        unsafe {
+            ptr::write(
+                res,
+                Value {
+                    data,
+                    len: value.len(),
+                    deleted,
+                },
+            )
+        };
+        res
+    }
+
+    // the first node with a key >= `key`, null if none
+    fn find_greater_or_equal(&self, key: &[u8]) -> *mut Node {
+        let mut x = self.head;
+        let mut level = self.height.load(Ordering::Acquire) - 1;
+        loop {
+            // safety: published nodes are never freed while the memtable lives
+            let next = unsafe { (*x).next(level) };
+            if !next.is_null() && unsafe { (*next).key() } < key {
+                x = next;
+            } else if level == 0 {
+                return next;
+            } else {
+                level -= 1;
+            }
+        }
+    }
+
+    // (prev, next) at `level` with prev.key < key <= next.key, searching from `before`
+    fn find_splice(&self, key: &[u8], level: usize, before: *mut Node) -> (*mut Node, *mut Node) {
+        let mut prev = before;
+        loop {
+            // safety: published nodes are never freed while the memtable lives
+            let next = unsafe { (*prev).next(level) };
+            if next.is_null() || unsafe { (*next).key() } >= key {
+                return (prev, next);
+            }
+            prev = next;
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, deleted: bool) {
+        let new_value = self.alloc_value(value, deleted);
+        let key = key.as_bytes();
+
+        let mut prev = [self.head; MAX_HEIGHT];
+        let mut next = [ptr::null_mut(); MAX_HEIGHT];
+        let mut before = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            (prev[level], next[level]) = self.find_splice(key, level, before);
+            before = prev[level];
+        }
+        if !next[0].is_null() && unsafe { (*next[0]).key() } == key {
+            self.replace_value(next[0], new_value);
+            return;
+        }
+
+        let height = random_height();
+        self.height.fetch_max(height, Ordering::AcqRel);
+        let node = Self::alloc_node(&self.arena, key, new_value);
+        for level in 0..height {
+            loop {
+                // safety: node is not published at this level yet, prev nodes are never freed
+                unsafe {
+                    (*node).next[level].store(next[level], Ordering::Relaxed);
+                    let linked = (*prev[level]).next[level].compare_exchange(
+                        next[level],
+                        node,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    if linked.is_ok() {
+                        break;
+                    }
+                }
+                // another writer linked a node here, search again from prev
+                (prev[level], next[level]) = self.find_splice(key, level, prev[level]);
+                if level == 0 && !next[0].is_null() && unsafe { (*next[0]).key() } == key {
+                    // the same key was inserted concurrently, the unlinked node stays in the arena
+                    self.replace_value(next[0], new_value);
+                    return;
+                }
+            }
+        }
+    }
+
+    // the old value stays in the arena, and in the byte size
+    fn replace_value(&self, node: *mut Node, value: *mut Value) {
+        // safety: published nodes and values are never freed while the memtable lives
+        unsafe { (*node).value.store(value, Ordering::Release) };
+    }
+}
+
+impl MemTable for SkipList {
+    fn get(&self, key: &str) -> Option<(String, bool)> {
+        let node = self.find_greater_or_equal(key.as_bytes());
+        if node.is_null() {
+            return None;
+        }
+        // safety: published nodes and values are never freed while the memtable lives
+        let node = unsafe { &*node };
+        if node.key() != key.as_bytes() {
+            return None;
+        }
+        let value = unsafe { &*node.value.load(Ordering::Acquire) };
+        if value.deleted {
+            return Some(("".to_string(), true));
+        }
+        Some((value.as_str().to_string(), false))
+    }
+
+    fn put(&self, key: &str, value: &str) {
+        self.set(key, value, false);
+    }
+
+    fn del(&self, key: &str) {
+        self.set(key, "", true);
+    }
+
+    // memory held by the arena, including node headers and overwritten values
+    fn byte_size(&self) -> usize {
+        self.arena.allocated() - self.head_bytes
+    }
+
+    fn range<'a>(&'a self, start: &'a str, end: &'a str) -> Box<dyn Iterator<Item = KVEntry> + 'a> {
+        Box::new(SkipListIter {
+            node: self.find_greater_or_equal(start.as_bytes()),
+            end: Some(end.as_bytes()),
+            _memtable: PhantomData,
+        })
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = KVEntry> + '_> {
+        Box::new(SkipListIter {
+            // safety: the head is never freed while the memtable lives
+            node: unsafe { (*self.head).next(0) },
+            end: None,
+            _memtable: PhantomData,
+        })
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// sorted entries, writes during the iteration may or may not be seen
+pub struct SkipListIter<'a> {
+    node: *mut Node,
+    end: Option<&'a [u8]>, // included
+    _memtable: PhantomData<&'a SkipList>,
+}
+
+impl Iterator for SkipListIter<'_> {
+    type Item = KVEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node.is_null() {
+            return None;
+        }
+        // safety: published nodes and values are never freed while the memtable lives
+        let node = unsafe { &*self.node };
+        if self.end.is_some_and(|end| node.key() > end) {
+            return None;
+        }
+        self.node = node.next(0);
+        let value = unsafe { &*node.value.load(Ordering::Acquire) };
+        // safety: keys are copied from a &str
+        let key = unsafe { str::from_utf8_unchecked(node.key()) };
+        Some((key.to_string(), value.as_str().to_string(), value.deleted))
+    }
+}
+
+impl fmt::Debug for SkipList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipList")
+            .field(
+                "store",
+                &self
+                    .iter()
+                    .map(|(k, v, deleted)| (k, (v, deleted)))
+                    .collect::<Vec<_>>(),
+            )
+            .field("byte_size", &self.byte_size())
+            .finish()
+    }
+}
@@ -1,25 +1,33 @@
 #[derive(Debug, Clone)]
 pub struct SparseIndex {
-    pub index: Vec<(String, u64)>,
+    pub index: Vec<(String, u64, u64)>, // first key, block offset, block length on disk
 }
 
 impl SparseIndex {
-    pub fn new(index: Vec<(String, u64)>) -> Self {
+    pub fn new(index: Vec<(String, u64, u64)>) -> Self {
         Self { index }
     }
 
-    pub fn get_containing_block_offset(&self, key: &str) -> Option<u64> {
+    /// return (block offset, block length)
+    pub fn get_containing_block(&self, key: &str) -> Option<(u64, u64)> {
         let res = self
             .index
-            .binary_search_by_key(&key.to_string(), |(k, _)| k.to_string()); // TODO: a lot of to_string
+            .binary_search_by_key(&key.to_string(), |(k, _, _)| k.to_string()); // TODO: a lot of to_string
         match res {
-            Ok(idx) => Some(self.index[idx].1),
+            Ok(idx) => Some((self.index[idx].1, self.index[idx].2)),
             Err(idx) => {
                 if idx == 0 {
                     return None;
                 }
-                Some(self.index[idx - 1].1)
+                Some((self.index[idx - 1].1, self.index[idx - 1].2))
             }
         }
     }
+
+    // position of the first block that may contain keys >= `key`
+    pub fn block_position(&self, key: &str) -> usize {
+        self.index
+            .partition_point(|(k, _, _)| k.as_str() <= key)
+            .saturating_sub(1)
+    }
 }
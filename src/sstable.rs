@@ -1,63 +1,260 @@
-use std::fs;
-use std::sync::Mutex;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec;
 
-use crate::reader::CachedReader;
+use crate::cache::BlockCache;
+use crate::compression::decompress_block;
+use crate::filter::BloomFilter;
+use crate::layout::{DataBlock, KVBlockIter};
+use crate::merge::KVEntry;
+use crate::options::ReadMode;
+use crate::reader::{CachedReader, MmapReader};
 use crate::sparseindex::SparseIndex;
+use anyhow::Context;
 use anyhow::Result;
-use anyhow::anyhow;
-use log::error;
-use log::info;
 
 #[derive(Debug)]
 pub struct SSTable {
     pub sparse_index: SparseIndex,
-    reader: Mutex<CachedReader>, // TODO: remove mutex, lock free data structure? each read thread create its own cache?
+    reader: TableReader,
+    file: Arc<File>, // kept open, iterators still read it after another process removes the file
     pub file_size: u64,
     pub filename: String,
+    pub smallest_key: String,
+    pub largest_key: String,
+    filter: Option<BloomFilter>, // None if written without a filter policy
+    being_compacted: AtomicBool, // reserved by a running compaction
 }
 
-impl Drop for SSTable {
-    fn drop(&mut self) {
-        match fs::remove_file(&self.filename) {
-            Err(err) => error!("failed to remove sstable file {}: {:?}", self.filename, err),
-            Ok(_) => info!("removed sstable file {}", self.filename),
-        }
+#[derive(Debug)]
+enum TableReader {
+    Cached(Box<Mutex<CachedReader>>), // TODO: remove mutex, lock free data structure? each read thread create its own cache?
+    Mmap(MmapReader),                 // read only mapping, no lock needed
+}
+
+impl TableReader {
+    fn last_key_of_block(&self, block_offset: u64, block_len: u64) -> Result<Option<String>> {
+        let last = match self {
+            TableReader::Cached(reader) => reader
+                .lock()
+                .unwrap()
+                .kv_block_iter(block_offset, block_len)?
+                .last(),
+            TableReader::Mmap(reader) => {
+                let block = reader.data_block(block_offset, block_len)?;
+                DataBlock::new(&block).iter().last()
+            }
+        };
+        Ok(last.map(|(k, _, _)| k))
     }
 }
 
 impl SSTable {
-    pub fn new(filename: &str) -> Result<Self> {
-        let mut reader = CachedReader::new(filename.to_string());
-        let index = reader.read_sparse_index()?;
-        let file_size = reader.get_file_size()?;
+    // the block cache is only used by `ReadMode::Buffered`, mmap relies on the OS page cache
+    pub fn new(
+        filename: &str,
+        read_mode: ReadMode,
+        block_cache: Option<Arc<BlockCache>>,
+    ) -> Result<Self> {
+        // one handle per sstable, shared by its reader and iterators
+        let file = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .open(filename)
+                .with_context(|| format!("failed to open sstable file {}", filename))?,
+        );
+        let (reader, (index, filter), file_size) = match read_mode {
+            ReadMode::Buffered => {
+                let mut reader =
+                    CachedReader::new(filename.to_string(), Arc::clone(&file), block_cache)?;
+                let meta = reader.read_meta()?;
+                let file_size = reader.get_file_size()?;
+                (
+                    TableReader::Cached(Box::new(Mutex::new(reader))),
+                    meta,
+                    file_size,
+                )
+            }
+            ReadMode::Mmap => {
+                let reader = MmapReader::new(filename.to_string(), &file)?;
+                let meta = reader.read_meta()?;
+                let file_size = reader.get_file_size();
+                (TableReader::Mmap(reader), meta, file_size)
+            }
+        };
+        // key range: first key of the first block, last key of the last block
+        let smallest_key = index.first().map(|(k, _, _)| k.clone()).unwrap_or_default();
+        let largest_key = match index.last() {
+            Some((_, offset, len)) => reader.last_key_of_block(*offset, *len)?.unwrap_or_default(),
+            None => String::new(),
+        };
         let sparseindex = SparseIndex::new(index);
         Ok(Self {
             sparse_index: sparseindex,
-            reader: Mutex::new(reader),
+            reader,
+            file,
             file_size,
             filename: filename.to_string(),
+            smallest_key,
+            largest_key,
+            filter,
+            being_compacted: AtomicBool::new(false),
         })
     }
 
-    /// return (value, deleted)
-    pub fn get(&self, key: &str) -> Result<(String, bool)> {
-        let block_offset = self
-            .sparse_index
-            .get_containing_block_offset(key)
-            .ok_or(anyhow!("not found in current sstable"))?;
+    pub fn is_being_compacted(&self) -> bool {
+        self.being_compacted.load(Ordering::Acquire)
+    }
+
+    pub fn set_being_compacted(&self, being_compacted: bool) {
+        self.being_compacted
+            .store(being_compacted, Ordering::Release);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sparse_index.index.is_empty()
+    }
 
-        let mut reader = self.reader.lock().unwrap();
-        reader.read_key(block_offset, key)
+    // whether the key range of this sstable overlaps [smallest, largest]
+    pub fn overlaps(&self, smallest: &str, largest: &str) -> bool {
+        !self.is_empty()
+            && self.smallest_key.as_str() <= largest
+            && smallest <= self.largest_key.as_str()
+    }
+
+    /// return (value, deleted), None if the key is not in this sstable,
+    /// Err only if the sstable can't be read
+    pub fn get(&self, key: &str) -> Result<Option<(String, bool)>> {
+        if self
+            .filter
+            .as_ref()
+            .is_some_and(|f| !f.may_contain(key.as_bytes()))
+        {
+            return Ok(None);
+        }
+        let Some((block_offset, block_len)) = self.sparse_index.get_containing_block(key) else {
+            return Ok(None);
+        };
+
+        match &self.reader {
+            TableReader::Cached(reader) => {
+                reader
+                    .lock()
+                    .unwrap()
+                    .read_key(block_offset, block_len, key)
+            }
+            TableReader::Mmap(reader) => reader.read_key(block_offset, block_len, key),
+        }
+        .with_context(|| format!("failed to read sstable {}", self.filename))
     }
 
     pub fn dump(&self) {
-        let mut guard = self.reader.lock().unwrap();
-        for (_, offset) in &self.sparse_index.index {
-            for (k, v, deleted) in guard.kv_block_iter(*offset).unwrap() {
-                println!("key = `{}`, val = `{}`, deleted = {}", k, v, deleted);
+        for (_, offset, len) in &self.sparse_index.index {
+            match &self.reader {
+                TableReader::Cached(reader) => {
+                    let mut guard = reader.lock().unwrap();
+                    Self::dump_block(guard.kv_block_iter(*offset, *len).unwrap());
+                }
+                TableReader::Mmap(reader) => {
+                    let block = reader.data_block(*offset, *len).unwrap();
+                    Self::dump_block(DataBlock::new(&block).iter());
+                }
             }
         }
     }
+
+    fn dump_block(iter: KVBlockIter<'_>) {
+        for (k, v, deleted) in iter {
+            println!("key = `{}`, val = `{}`, deleted = {}", k, v, deleted);
+        }
+    }
+}
+
+/// the first read error of the sstable iterators sharing it, an iterator ends at its error,
+/// so a merged result is only complete if `take_error` returns Ok once the iteration is done
+#[derive(Debug, Default, Clone)]
+pub struct IterError(Arc<Mutex<Option<anyhow::Error>>>);
+
+impl IterError {
+    fn set(&self, err: anyhow::Error) {
+        self.0.lock().unwrap().get_or_insert(err);
+    }
+
+    pub fn take_error(&self) -> Result<()> {
+        match self.0.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
 }
 
 // a iterator for sstable file owning its own cache
+pub struct SSTableIterator {
+    sstable: Arc<SSTable>, // keeps the file alive while iterating
+    error: IterError,
+    read_buf: Vec<u8>,               // buffer for reading a block from the file
+    block_index: usize,              // the index inside the sparse index of the next block
+    entries: vec::IntoIter<KVEntry>, // remaining entries of the current block
+}
+
+impl SSTableIterator {
+    // reads with positions, so iterators share the file of the sstable
+    pub fn new(sstable: Arc<SSTable>, error: IterError) -> Result<Self> {
+        Ok(Self {
+            sstable,
+            error,
+            read_buf: vec![],
+            block_index: 0,
+            entries: vec![].into_iter(),
+        })
+    }
+
+    // start from the block that may contain `key`, smaller keys of that block are still returned
+    pub fn seek(sstable: Arc<SSTable>, key: &str, error: IterError) -> Result<Self> {
+        let mut iter = Self::new(sstable, error)?;
+        iter.block_index = iter.sstable.sparse_index.block_position(key);
+        Ok(iter)
+    }
+
+    // Ok(bool) => true: read a block, false: no more block
+    fn load_next_block(&mut self) -> Result<bool> {
+        let Some((_, offset, len)) = self.sstable.sparse_index.index.get(self.block_index) else {
+            return Ok(false);
+        };
+        self.block_index += 1;
+        self.read_buf.resize(*len as usize, 0);
+        self.sstable
+            .file
+            .read_exact_at(&mut self.read_buf, *offset)?;
+        let block = decompress_block(&self.read_buf)?;
+        let entries: Vec<KVEntry> = DataBlock::new(&block).iter().collect();
+        self.entries = entries.into_iter();
+        Ok(true)
+    }
+}
+
+impl Iterator for SSTableIterator {
+    type Item = KVEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(kv) = self.entries.next() {
+                return Some(kv);
+            }
+            match self.load_next_block() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                // the result is truncated here, the reader checks `IterError::take_error`
+                Err(err) => {
+                    let err =
+                        err.context(format!("failed to read sstable {}", self.sstable.filename));
+                    self.error.set(err);
+                    self.block_index = self.sstable.sparse_index.index.len();
+                    return None;
+                }
+            }
+        }
+    }
+}
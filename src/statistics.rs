@@ -0,0 +1,45 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// counters of an engine, updated by writers and background threads
+#[derive(Debug, Default)]
+pub struct Statistics {
+    slowdown_count: AtomicU64,
+    slowdown_micros: AtomicU64,
+    stop_count: AtomicU64,
+    stop_micros: AtomicU64,
+}
+
+/// write stalls so far, slowed down writes are delayed, stopped writes wait for background work
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StallStatistics {
+    pub slowdown_count: u64,
+    pub slowdown_micros: u64,
+    pub stop_count: u64,
+    pub stop_micros: u64,
+}
+
+impl Statistics {
+    pub fn record_slowdown(&self, duration: Duration) {
+        self.slowdown_count.fetch_add(1, Ordering::Relaxed);
+        self.slowdown_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_stop(&self, duration: Duration) {
+        self.stop_count.fetch_add(1, Ordering::Relaxed);
+        self.stop_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stalls(&self) -> StallStatistics {
+        StallStatistics {
+            slowdown_count: self.slowdown_count.load(Ordering::Relaxed),
+            slowdown_micros: self.slowdown_micros.load(Ordering::Relaxed),
+            stop_count: self.stop_count.load(Ordering::Relaxed),
+            stop_micros: self.stop_micros.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    mem,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use crate::{memtable::MemTable, merge::KVEntry};
+
+/// unsorted log of writes, appends are cheap, lookups scan from the newest write,
+/// entries are sorted when iterated, suits bulk loads that are rarely read before the flush
+#[derive(Debug)]
+pub struct VectorTable {
+    entries: Mutex<Vec<KVEntry>>, // in write order
+    byte_size: AtomicUsize,       // every write is kept, overwrites are counted
+}
+
+impl VectorTable {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(vec![]),
+            byte_size: AtomicUsize::new(0),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, deleted: bool) {
+        self.entries
+            .lock()
+            .unwrap()
+            .push((key.to_string(), value.to_string(), deleted));
+        self.byte_size.fetch_add(
+            mem::size_of::<KVEntry>() + key.len() + value.len(),
+            Ordering::Relaxed,
+        );
+    }
+
+    // entries matching the predicate sorted by key, the newest write of each key wins
+    fn sorted(&self, predicate: impl Fn(&str) -> bool) -> Vec<KVEntry> {
+        let mut entries: Vec<KVEntry> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, _, _)| predicate(k))
+            .cloned()
+            .collect();
+        // stable, so writes of the same key stay in write order
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut res: Vec<KVEntry> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match res.last_mut() {
+                Some(last) if last.0 == entry.0 => *last = entry,
+                _ => res.push(entry),
+            }
+        }
+        res
+    }
+}
+
+impl Default for VectorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemTable for VectorTable {
+    fn get(&self, key: &str) -> Option<(String, bool)> {
+        let entries = self.entries.lock().unwrap();
+        let (_, value, deleted) = entries.iter().rev().find(|(k, _, _)| k == key)?;
+        Some((value.clone(), *deleted))
+    }
+
+    fn put(&self, key: &str, value: &str) {
+        self.set(key, value, false);
+    }
+
+    fn del(&self, key: &str) {
+        self.set(key, "", true);
+    }
+
+    fn byte_size(&self) -> usize {
+        self.byte_size.load(Ordering::Relaxed)
+    }
+
+    fn range<'a>(&'a self, start: &'a str, end: &'a str) -> Box<dyn Iterator<Item = KVEntry> + 'a> {
+        Box::new(self.sorted(|k| start <= k && k <= end).into_iter())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = KVEntry> + '_> {
+        Box::new(self.sorted(|_| true).into_iter())
+    }
+}
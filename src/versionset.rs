@@ -4,24 +4,64 @@ use crate::{memtable::MemTable, sstable::SSTable};
 
 #[derive(Debug)]
 pub struct Version {
-    pub imm_memtables: Vec<Arc<MemTable>>,
-    pub sstables: Vec<Arc<SSTable>>,
+    pub imm_memtables: Vec<Arc<dyn MemTable>>,
+    // level 0: flushed sstables, key ranges may overlap, oldest at the start
+    // level 1..n: sstables sorted by key range, key ranges never overlap inside a level
+    pub levels: Vec<Vec<Arc<SSTable>>>,
 }
 
 impl Version {
     pub fn new() -> Self {
         Self {
             imm_memtables: vec![],
-            sstables: vec![],
+            levels: vec![vec![]],
         }
     }
+
+    pub fn level(&self, level: usize) -> &[Arc<SSTable>] {
+        self.levels.get(level).map(|l| l.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn level_mut(&mut self, level: usize) -> &mut Vec<Arc<SSTable>> {
+        if self.levels.len() <= level {
+            self.levels.resize(level + 1, vec![]);
+        }
+        &mut self.levels[level]
+    }
+
+    pub fn level_byte_size(&self, level: usize) -> u64 {
+        self.level(level).iter().map(|s| s.file_size).sum()
+    }
+
+    // all sstables with their level, level by level
+    pub fn sstables(&self) -> impl Iterator<Item = (usize, &Arc<SSTable>)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, sstables)| sstables.iter().map(move |s| (level, s)))
+    }
+
+    // sstables of a level whose key range overlaps [smallest, largest]
+    pub fn overlapping(&self, level: usize, smallest: &str, largest: &str) -> Vec<Arc<SSTable>> {
+        self.level(level)
+            .iter()
+            .filter(|s| s.overlaps(smallest, largest))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clone for Version {
     fn clone(&self) -> Self {
         Self {
             imm_memtables: self.imm_memtables.clone(),
-            sstables: self.sstables.clone(),
+            levels: self.levels.clone(),
         }
     }
 }
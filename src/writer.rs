@@ -1,6 +1,19 @@
-use std::{fs::OpenOptions, io::Write};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    mem,
+    sync::Arc,
+};
 
-use crate::layout::Layout;
+use crate::{
+    common::next_log_file_name,
+    compression::compress_block,
+    filter::FilterBuilder,
+    layout::{DataBlocks, Footer, SparseIndexBlock},
+    options::{Compression, FilterPolicy, Options},
+    ratelimiter::RateLimiter,
+};
 use anyhow::Result;
 
 pub struct Writer {}
@@ -9,21 +22,144 @@ impl Writer {
     pub fn write(
         memtable: impl IntoIterator<Item = (String, String, bool)>,
         filename: &str,
+        options: &Options,
+        rate_limiter: Option<Arc<RateLimiter>>,
     ) -> Result<()> {
-        let mut file = OpenOptions::new()
+        let mut writer = SstWriter::new(filename, options, rate_limiter)?;
+        for (k, v, deleted) in memtable {
+            writer.add(&k, &v, deleted)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// write into new files in `dir`, roll over to the next file once the current one
+    /// reaches `target_file_size` bytes, no limit if None, return the filenames
+    pub fn write_split(
+        kvs: impl IntoIterator<Item = (String, String, bool)>,
+        dir: &str,
+        options: &Options,
+        target_file_size: Option<u64>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Vec<String>> {
+        let mut filenames = vec![next_log_file_name(dir)];
+        let mut writer = SstWriter::new(&filenames[0], options, rate_limiter.clone())?;
+        for (k, v, deleted) in kvs {
+            if target_file_size.is_some_and(|target| writer.estimated_size() >= target) {
+                writer.finish()?;
+                filenames.push(next_log_file_name(dir));
+                writer = SstWriter::new(filenames.last().unwrap(), options, rate_limiter.clone())?;
+            }
+            writer.add(&k, &v, deleted)?;
+        }
+        writer.finish()?;
+        Ok(filenames)
+    }
+}
+
+/// streaming sstable writer, data blocks are written as they fill,
+/// only the sparse index and the filter are kept in memory until `finish`
+pub struct SstWriter {
+    file: File,
+    compression: Compression,
+    block_size: usize,
+    data_blocks: DataBlocks,
+    filter: Option<FilterBuilder>,
+    first_keys: VecDeque<String>, // first keys of the blocks not written yet
+    index: Vec<(String, u64, u64)>,
+    offset: u64, // bytes written so far
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl SstWriter {
+    pub fn new(
+        filename: &str,
+        options: &Options,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
             .open(filename)?;
+        let filter = match options.filter_policy {
+            FilterPolicy::None => None,
+            FilterPolicy::Bloom { bits_per_key } => Some(FilterBuilder::new(bits_per_key)),
+        };
+        Ok(Self {
+            file,
+            compression: options.compression,
+            block_size: options.block_size,
+            data_blocks: DataBlocks::new(options.block_size),
+            filter,
+            first_keys: VecDeque::new(),
+            index: vec![],
+            offset: 0,
+            rate_limiter,
+        })
+    }
 
-        let blocks_chain = Layout::build(memtable)?;
-
-        for blocks in blocks_chain {
-            for block in blocks.inner {
-                file.write_all(&block.inner)?;
-            }
+    // keys must be added in ascending order
+    pub fn add(&mut self, key: &str, val: &str, deleted: bool) -> Result<()> {
+        if self
+            .data_blocks
+            .add(key.as_bytes(), val.as_bytes(), deleted)?
+        {
+            self.first_keys.push_back(key.to_string());
         }
+        if let Some(filter) = &mut self.filter {
+            filter.add(key.as_bytes());
+        }
+        for block in self.data_blocks.take_finished() {
+            self.write_block(&block)?;
+        }
+        Ok(())
+    }
 
+    // bytes written plus the raw size of the block being filled
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.data_blocks.current_len() as u64
+    }
+
+    fn write_block(&mut self, raw: &[u8]) -> Result<()> {
+        let block = compress_block(raw, self.compression)?;
+        self.write_all(&block)?;
+        let first_key = self.first_keys.pop_front().unwrap_or_default();
+        self.index
+            .push((first_key, self.offset, block.len() as u64));
+        self.offset += block.len() as u64;
         Ok(())
     }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.request(buf.len() as u64);
+        }
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    /// write the last data block, the sparse index, the filter and the footer, then fsync,
+    /// the file must be durable before it is installed in a version, return the file size
+    pub fn finish(mut self) -> Result<u64> {
+        let data_blocks = mem::replace(&mut self.data_blocks, DataBlocks::new(self.block_size));
+        for block in data_blocks.finish() {
+            self.write_block(&block)?;
+        }
+
+        let index_block = SparseIndexBlock::encode(&self.index);
+        let filter_block = self.filter.as_ref().map(|f| f.finish()).unwrap_or_default();
+        let index_offset = self.offset;
+        let filter_offset = index_offset + index_block.len() as u64;
+        let footer = Footer {
+            index: (index_offset, index_block.len() as u64),
+            filter: (filter_offset, filter_block.len() as u64),
+        }
+        .encode();
+        self.write_all(&index_block)?;
+        self.write_all(&filter_block)?;
+        self.write_all(&footer)?;
+        self.file.sync_all()?;
+        Ok(filter_offset + (filter_block.len() + footer.len()) as u64)
+    }
 }
@@ -1,118 +1,1336 @@
 use mossdb::common::MossError;
-use mossdb::engine::Engine;
-use std::fs::remove_file;
-use std::thread::sleep;
-use std::time::Duration;
+use mossdb::compaction::{
+    AdjacentPairs, CompactionFilter, CompactionStrategy, FilterDecision, Leveled, SizeTiered,
+};
+use mossdb::engine::{Engine, OrphanReport};
+use mossdb::options::{Compression, FilterPolicy, MemTableKind, Options, ReadMode};
+use mossdb::ratelimiter::RateLimiter;
+use std::env::temp_dir;
+use std::fs::{
+    OpenOptions, create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, write,
+};
+use std::process;
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-fn clear_log_files(engine: &Engine) {
-    let files = engine.list_sorted_log_files().unwrap();
-    for p in files {
-        remove_file(p).unwrap();
+// a fresh database directory for each test, so tests can run in parallel
+fn test_dir(name: &str) -> String {
+    let dir = temp_dir().join(format!("mossdb_{}_{}", process::id(), name));
+    let _ = remove_dir_all(&dir);
+    dir.to_string_lossy().to_string()
+}
+
+// close first, so no flush writes a new file after the clear
+fn close_and_clear(engine: &Engine) {
+    engine.close().unwrap();
+    remove_dir_all(&engine.sstables_dir).unwrap();
+}
+
+fn log_file_count(dir: &str) -> usize {
+    read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "log")
+        })
+        .count()
+}
+
+// poll until background work reaches the expected state, fail after a deadline
+fn wait_until(mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "background work did not finish in time"
+        );
+        sleep(Duration::from_millis(10));
     }
 }
 
+// level 0 is compacted down to the limit
+fn wait_level0_files(e: &Engine, limit: usize) {
+    wait_until(|| e.version.read().unwrap().level(0).len() <= limit);
+}
+
 // put
 #[test]
 fn test_put() {
-    let e = Engine::new("./", 10, 10).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(10)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("put"), options).unwrap();
 
-    e.put("1", "1");
+    e.put("1", "1").unwrap();
     assert_eq!("1", e.get("1").unwrap());
 
-    clear_log_files(&e);
+    close_and_clear(&e);
 }
 
 // mutliple put
 #[test]
 fn test_multiple_put() {
-    let e = Engine::new("./", 10, 10).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(10)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("multiple_put"), options).unwrap();
 
-    e.put("1", "1");
+    e.put("1", "1").unwrap();
     assert_eq!("1", e.get("1").unwrap());
 
-    e.put("2", "2");
+    e.put("2", "2").unwrap();
     assert_eq!("2", e.get("2").unwrap());
 
-    clear_log_files(&e);
+    close_and_clear(&e);
 }
 
 // put override
 #[test]
 fn test_put_override() {
-    let e = Engine::new("./", 10, 10).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(10)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("put_override"), options).unwrap();
 
-    e.put("1", "1");
+    e.put("1", "1").unwrap();
     assert_eq!("1", e.get("1").unwrap());
 
-    e.put("2", "2");
+    e.put("2", "2").unwrap();
     assert_eq!("2", e.get("2").unwrap());
 
-    e.put("1", "3");
+    e.put("1", "3").unwrap();
     assert_eq!("3", e.get("1").unwrap());
 
-    clear_log_files(&e);
+    close_and_clear(&e);
 }
 
 // del
 #[test]
 fn test_del() {
-    let e = Engine::new("./", 10, 10).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(10)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("del"), options).unwrap();
 
-    e.put("1", "1");
+    e.put("1", "1").unwrap();
     assert_eq!("1", e.get("1").unwrap());
 
-    e.put("2", "2");
+    e.put("2", "2").unwrap();
     assert_eq!("2", e.get("2").unwrap());
 
-    e.del("1");
+    e.del("1").unwrap();
     assert!(e.get("1").is_err_and(|e| e == MossError::KeyNotFound));
 
-    clear_log_files(&e);
+    close_and_clear(&e);
 }
 
 // put trigger flush
 #[test]
 fn test_put_and_flush() {
-    let e = Engine::new("./", 4, 10).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(4)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("put_and_flush"), options).unwrap();
     assert_eq!(0, e.list_sorted_log_files().unwrap().len());
 
-    e.put("1", "111");
+    e.put("1", "111").unwrap();
     assert_eq!("111", e.get("1").unwrap());
-    assert_eq!(0, e.list_sorted_log_files().unwrap().len());
+    wait_until(|| e.list_sorted_log_files().unwrap().len() == 1);
 
-    e.put("2", "222");
+    e.put("2", "222").unwrap();
     assert_eq!("222", e.get("2").unwrap());
-    sleep(Duration::from_secs(1)); // wait for the flush thread to finish flushing, need to find a better test method
-    assert_eq!(2, e.list_sorted_log_files().unwrap().len());
+    wait_until(|| e.list_sorted_log_files().unwrap().len() == 2);
 
-    clear_log_files(&e);
+    close_and_clear(&e);
 }
 
 // compact
 #[test]
 fn test_put_del_compact() {
-    let e = Engine::new("./", 1, 2).unwrap();
-    clear_log_files(&e);
+    let options = Options::builder()
+        .memtable_flush_limit(1)
+        .sstable_compact_limit(2)
+        .build();
+    let e = Engine::open(&test_dir("put_del_compact"), options).unwrap();
     assert_eq!(0, e.list_sorted_log_files().unwrap().len());
 
-    e.put("1", "1");
+    e.put("1", "1").unwrap();
     assert_eq!("1", e.get("1").unwrap());
 
-    e.put("2", "2");
+    e.put("2", "2").unwrap();
     assert_eq!("2", e.get("2").unwrap());
 
-    e.put("1", "111");
+    e.put("1", "111").unwrap();
     assert_eq!("111", e.get("1").unwrap());
 
-    e.del("2");
-    sleep(Duration::from_secs(1));
-    assert_eq!(2, e.list_sorted_log_files().unwrap().len());
+    e.del("2").unwrap();
+    e.flush().unwrap();
+    wait_until(|| e.list_sorted_log_files().unwrap().len() == 2);
     assert!(e.get("2").is_err_and(|e| e == MossError::KeyNotFound));
 
-    clear_log_files(&e);
+    close_and_clear(&e);
+}
+
+// read flushed sstables through mmap
+#[test]
+fn test_mmap_read_mode() {
+    let options = Options {
+        memtable_flush_limit: 4,
+        sstable_compact_limit: 10,
+        read_mode: ReadMode::Mmap,
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("mmap_read_mode"), options).unwrap();
+
+    e.put("1", "111").unwrap();
+    e.put("2", "222").unwrap();
+    e.flush().unwrap();
+    assert_eq!(2, e.list_sorted_log_files().unwrap().len());
+    assert_eq!("111", e.get("1").unwrap());
+    assert_eq!("222", e.get("2").unwrap());
+
+    close_and_clear(&e);
+}
+
+// lookup keys spread across restart points and blocks of a flushed sstable
+#[test]
+fn test_get_many_keys_from_sstable() {
+    let options = Options::builder()
+        .memtable_flush_limit(1024 * 1024)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("get_many_keys_from_sstable"), options).unwrap();
+
+    for i in 0..2000 {
+        e.put(&format!("key{:05}", i * 2), &format!("val{}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    assert_eq!(1, e.list_sorted_log_files().unwrap().len());
+
+    for i in 0..2000 {
+        assert_eq!(
+            format!("val{}", i),
+            e.get(&format!("key{:05}", i * 2)).unwrap()
+        );
+        assert!(
+            e.get(&format!("key{:05}", i * 2 + 1))
+                .is_err_and(|e| e == MossError::KeyNotFound)
+        );
+    }
+    assert!(e.get("a").is_err_and(|e| e == MossError::KeyNotFound));
+    assert!(e.get("z").is_err_and(|e| e == MossError::KeyNotFound));
+
+    close_and_clear(&e);
+}
+
+// keys sharing long prefixes survive flush and compaction
+#[test]
+fn test_shared_prefix_keys_compact() {
+    let options = Options::builder()
+        .memtable_flush_limit(512)
+        .sstable_compact_limit(2)
+        .build();
+    let e = Engine::open(&test_dir("shared_prefix_keys_compact"), options).unwrap();
+
+    for i in 0..200 {
+        e.put(&format!("tenant/0001/user/{:05}", i), &format!("{}", i))
+            .unwrap();
+    }
+    for i in (0..200).step_by(3) {
+        e.del(&format!("tenant/0001/user/{:05}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    wait_level0_files(&e, 2);
+
+    for i in 0..200 {
+        let res = e.get(&format!("tenant/0001/user/{:05}", i));
+        if i % 3 == 0 {
+            assert!(res.is_err_and(|e| e == MossError::KeyNotFound));
+        } else {
+            assert_eq!(format!("{}", i), res.unwrap());
+        }
+    }
+
+    close_and_clear(&e);
+}
+
+// compressed blocks are read back by both readers and by compaction
+#[test]
+fn test_compressed_blocks() {
+    for (compression, read_mode) in [
+        (Compression::Lz4, ReadMode::Buffered),
+        (Compression::Snappy, ReadMode::Mmap),
+    ] {
+        let options = Options {
+            memtable_flush_limit: 16 * 1024,
+            sstable_compact_limit: 2,
+            read_mode,
+            compression,
+            ..Options::default()
+        };
+        let e = Engine::open(&test_dir("compressed_blocks"), options).unwrap();
+
+        for i in 0..3000 {
+            e.put(&format!("key{:05}", i), &"v".repeat(i % 100))
+                .unwrap();
+        }
+        e.flush().unwrap();
+        wait_level0_files(&e, 2);
+
+        for i in 0..3000 {
+            assert_eq!("v".repeat(i % 100), e.get(&format!("key{:05}", i)).unwrap());
+        }
+
+        close_and_clear(&e);
+    }
+}
+
+// blocks are written at their actual length, a tiny flush makes a tiny file
+#[test]
+fn test_small_flush_small_file() {
+    let options = Options::builder()
+        .memtable_flush_limit(1024)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("small_flush_small_file"), options).unwrap();
+
+    e.put("1", "1").unwrap();
+    e.flush().unwrap();
+
+    let files = e.list_sorted_log_files().unwrap();
+    assert_eq!(1, files.len());
+    assert!(metadata(&files[0]).unwrap().len() < 128);
+    assert_eq!("1", e.get("1").unwrap());
+
+    close_and_clear(&e);
+}
+
+// leveled compaction pushes data below level 0 and keeps overrides and deletes visible
+#[test]
+fn test_leveled_compaction() {
+    let options = Options {
+        memtable_flush_limit: 2 * 1024,
+        sstable_compact_limit: 2,
+        compaction_strategy: Arc::new(Leveled::new(8 * 1024, 4, 4).unwrap()),
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("leveled_compaction"), options).unwrap();
+    // a single level, a flat multiplier or an empty base would break the level targets
+    for (base, multiplier, num_levels) in [(1024, 4, 1), (1024, 1, 4), (0, 4, 4)] {
+        assert!(Leveled::new(base, multiplier, num_levels).is_err());
+    }
+
+    for round in 0..3 {
+        for i in 0..500 {
+            e.put(
+                &format!("key{:05}", (i * 7) % 500),
+                &format!("{}-{}", round, i),
+            )
+            .unwrap();
+        }
+    }
+    for i in (0..500).step_by(5) {
+        e.del(&format!("key{:05}", (i * 7) % 500)).unwrap();
+    }
+    e.flush().unwrap();
+    wait_level0_files(&e, 2);
+
+    assert!(!e.version.read().unwrap().level(1).is_empty());
+    for i in 0..500 {
+        let res = e.get(&format!("key{:05}", (i * 7) % 500));
+        if i % 5 == 0 {
+            assert!(res.is_err_and(|e| e == MossError::KeyNotFound));
+        } else {
+            assert_eq!(format!("2-{}", i), res.unwrap());
+        }
+    }
+
+    close_and_clear(&e);
+}
+
+// size-tiered compaction merges similar sized runs and keeps the run count under the limit
+#[test]
+fn test_size_tiered_compaction() {
+    let options = Options {
+        memtable_flush_limit: 1024,
+        sstable_compact_limit: 3,
+        compaction_strategy: Arc::new(SizeTiered {
+            size_ratio: 20,
+            min_merge_width: 2,
+            max_merge_width: 4,
+        }),
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("size_tiered_compaction"), options).unwrap();
+
+    for i in 0..2000 {
+        e.put(&format!("key{:05}", i % 700), &format!("{}", i))
+            .unwrap();
+    }
+    e.del("key00001").unwrap();
+    e.flush().unwrap();
+    wait_until(|| e.list_sorted_log_files().unwrap().len() <= 3);
+    for i in 1300..2000 {
+        let res = e.get(&format!("key{:05}", i % 700));
+        if i % 700 == 1 {
+            assert!(res.is_err_and(|e| e == MossError::KeyNotFound));
+        } else {
+            assert_eq!(format!("{}", i), res.unwrap());
+        }
+    }
+
+    close_and_clear(&e);
+}
+
+// drop keys of a deleted tenant and rewrite values of another one during compaction
+#[derive(Debug)]
+struct TenantFilter;
+
+impl CompactionFilter for TenantFilter {
+    fn filter(&self, _: usize, key: &str, value: &str) -> FilterDecision {
+        if key.starts_with("t1/") {
+            FilterDecision::Remove
+        } else if key.starts_with("t2/") && !value.starts_with("v2:") {
+            // entries may pass through several compactions, migrate only once
+            FilterDecision::ChangeValue(format!("v2:{}", value))
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
+
+#[test]
+fn test_compaction_filter() {
+    let options = Options {
+        memtable_flush_limit: 256,
+        sstable_compact_limit: 1,
+        compaction_filter: Some(Arc::new(TenantFilter)),
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("compaction_filter"), options).unwrap();
+
+    for i in 0..100 {
+        e.put(&format!("t{}/{:03}", i % 3, i), &format!("{}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    wait_level0_files(&e, 1);
+
+    for i in 0..100 {
+        let res = e.get(&format!("t{}/{:03}", i % 3, i));
+        match i % 3 {
+            0 => assert_eq!(format!("{}", i), res.unwrap()),
+            1 => assert!(res.is_err_and(|e| e == MossError::KeyNotFound)),
+            _ => assert_eq!(format!("v2:{}", i), res.unwrap()),
+        }
+    }
+
+    close_and_clear(&e);
+}
+
+// manual range compaction removes the tombstones left by bulk deletes
+#[test]
+fn test_compact_range() {
+    let options = Options::builder()
+        .memtable_flush_limit(512)
+        .sstable_compact_limit(100)
+        .build();
+    let e = Engine::open(&test_dir("compact_range"), options).unwrap();
+
+    for i in 0..100 {
+        e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    for i in 0..50 {
+        e.del(&format!("key{:03}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    let before = e.list_sorted_log_files().unwrap().len();
+    assert!(before > 1);
+
+    e.compact_range("key000", "key049").unwrap();
+    assert!(e.list_sorted_log_files().unwrap().len() < before);
+    for i in 0..100 {
+        let res = e.get(&format!("key{:03}", i));
+        if i < 50 {
+            assert!(res.is_err_and(|e| e == MossError::KeyNotFound));
+        } else {
+            assert_eq!(format!("{}", i), res.unwrap());
+        }
+    }
+
+    // everything deleted, nothing left on disk
+    for i in 50..100 {
+        e.del(&format!("key{:03}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    e.compact_range("key000", "key099").unwrap();
+    assert_eq!(0, e.list_sorted_log_files().unwrap().len());
+
+    close_and_clear(&e);
+}
+
+// scan merges memtable and sstables, newest value wins, deleted keys are skipped
+#[test]
+fn test_scan() {
+    let options = Options::builder()
+        .memtable_flush_limit(1024)
+        .sstable_compact_limit(3)
+        .build();
+    let e = Engine::open(&test_dir("scan"), options).unwrap();
+
+    for i in 0..300 {
+        e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+    }
+    for i in (0..300).step_by(2) {
+        e.put(&format!("key{:03}", i), &format!("{}-new", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    for i in (0..300).step_by(3) {
+        e.del(&format!("key{:03}", i)).unwrap();
+    }
+
+    let res = e.scan("key100", "key199").unwrap();
+    let expected: Vec<(String, String)> = (100..200)
+        .filter(|i| i % 3 != 0)
+        .map(|i| {
+            let val = if i % 2 == 0 {
+                format!("{}-new", i)
+            } else {
+                format!("{}", i)
+            };
+            (format!("key{:03}", i), val)
+        })
+        .collect();
+    assert_eq!(expected, res);
+    assert!(e.scan("key200", "key100").unwrap().is_empty());
+
+    close_and_clear(&e);
+}
+
+// compaction output below level 0 is split into size-bounded files
+#[test]
+fn test_split_compaction_output() {
+    let options = Options {
+        memtable_flush_limit: 4 * 1024,
+        sstable_compact_limit: 2,
+        target_file_size: Some(4 * 1024),
+        compaction_strategy: Arc::new(Leveled::new(1024 * 1024, 10, 3).unwrap()),
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("split_compaction_output"), options).unwrap();
+
+    for i in 0..1000 {
+        e.put(&format!("key{:05}", i), &format!("value{:05}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    wait_level0_files(&e, 2);
+
+    {
+        let version = e.version.read().unwrap();
+        let level = version.level(1);
+        assert!(level.len() > 1);
+        for s in level {
+            assert!(s.file_size < 8 * 1024);
+        }
+        for pair in level.windows(2) {
+            assert!(pair[0].largest_key < pair[1].smallest_key);
+        }
+    }
+    for i in 0..1000 {
+        assert_eq!(
+            format!("value{:05}", i),
+            e.get(&format!("key{:05}", i)).unwrap()
+        );
+    }
+
+    close_and_clear(&e);
+}
+
+// rate limited writes take at least size / rate, the rate can be changed at runtime
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(1024 * 1024);
+    let start = Instant::now();
+    for _ in 0..8 {
+        limiter.request(128 * 1024);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(800));
+
+    limiter.set_bytes_per_sec(0);
+    let start = Instant::now();
+    limiter.request(1024 * 1024 * 1024);
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    // compaction and flush writes go through the limiter
+    let options = Options {
+        memtable_flush_limit: 1024,
+        sstable_compact_limit: 2,
+        rate_limiter: Some(Arc::new(RateLimiter::new(1024 * 1024))),
+        rate_limit_flush: true,
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("rate_limiter"), options).unwrap();
+
+    for i in 0..500 {
+        e.put(&format!("key{:05}", i), &format!("{}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    for i in 0..500 {
+        assert_eq!(format!("{}", i), e.get(&format!("key{:05}", i)).unwrap());
+    }
+
+    close_and_clear(&e);
+}
+
+// several compaction threads and subcompactions keep every key readable
+#[test]
+fn test_parallel_compactions() {
+    let options = Options {
+        memtable_flush_limit: 2 * 1024,
+        sstable_compact_limit: 2,
+        target_file_size: Some(2 * 1024),
+        compaction_strategy: Arc::new(Leveled::new(8 * 1024, 4, 4).unwrap()),
+        max_background_compactions: 3,
+        max_subcompactions: 4,
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("parallel_compactions"), options).unwrap();
+
+    for round in 0..3 {
+        for i in 0..1000 {
+            e.put(
+                &format!("key{:05}", (i * 13) % 1000),
+                &format!("{}-{}", round, i),
+            )
+            .unwrap();
+        }
+    }
+    e.flush().unwrap();
+    wait_level0_files(&e, 2);
+
+    {
+        let version = e.version.read().unwrap();
+        for level in version.levels.iter().skip(1) {
+            for pair in level.windows(2) {
+                assert!(pair[0].largest_key < pair[1].smallest_key);
+            }
+        }
+    }
+    for i in 0..1000 {
+        assert_eq!(
+            format!("2-{}", i),
+            e.get(&format!("key{:05}", (i * 13) % 1000)).unwrap()
+        );
+    }
+    e.compact_range("key00000", "key00999").unwrap();
+    assert_eq!(1000, e.scan("key00000", "key00999").unwrap().len());
+
+    close_and_clear(&e);
+}
+
+// a slow flush stops writers until immutable memtables are flushed
+#[test]
+fn test_write_stall() {
+    let options = Options {
+        memtable_flush_limit: 1024,
+        sstable_compact_limit: 100,
+        rate_limiter: Some(Arc::new(RateLimiter::new(64 * 1024))),
+        rate_limit_flush: true,
+        max_imm_memtables: 2,
+        ..Options::default()
+    };
+    let e = Engine::open(&test_dir("write_stall"), options).unwrap();
+
+    for i in 0..1000 {
+        e.put(&format!("key{:05}", i), &format!("value{:05}", i))
+            .unwrap();
+        assert!(e.version.read().unwrap().imm_memtables.len() <= 2);
+    }
+    let stalls = e.statistics.stalls();
+    assert!(stalls.stop_count > 0);
+    assert!(stalls.stop_micros > 0);
+
+    e.flush().unwrap();
+    for i in 0..1000 {
+        assert_eq!(
+            format!("value{:05}", i),
+            e.get(&format!("key{:05}", i)).unwrap()
+        );
+    }
+
+    close_and_clear(&e);
+}
+
+// concurrent writers and readers on the skiplist memtable, across memtable swaps
+#[test]
+fn test_concurrent_writes() {
+    let options = Options::builder()
+        .memtable_flush_limit(16 * 1024)
+        .sstable_compact_limit(4)
+        .build();
+    let e = Engine::open(&test_dir("concurrent_writes"), options).unwrap();
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let e = Arc::clone(&e);
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    e.put(&format!("t{}/{:05}", t, i), &format!("{}", i))
+                        .unwrap();
+                    e.put(&format!("shared{:05}", i % 100), &format!("{}", t))
+                        .unwrap();
+                    assert_eq!(
+                        format!("{}", i),
+                        e.get(&format!("t{}/{:05}", t, i)).unwrap()
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for t in 0..8 {
+        for i in 0..2000 {
+            assert_eq!(
+                format!("{}", i),
+                e.get(&format!("t{}/{:05}", t, i)).unwrap()
+            );
+        }
+    }
+    for i in 0..100 {
+        assert!(e.get(&format!("shared{:05}", i)).is_ok());
+    }
+    assert_eq!(2000, e.scan("t3/", "t3/~").unwrap().len());
+    e.flush().unwrap();
+
+    close_and_clear(&e);
+}
+
+// every memtable kind gives the same answers, before and after the flush
+#[test]
+fn test_memtable_kinds() {
+    for kind in [
+        MemTableKind::SkipList,
+        MemTableKind::Hash,
+        MemTableKind::Vector,
+    ] {
+        let options = Options {
+            memtable_flush_limit: 2 * 1024,
+            memtable: kind,
+            ..Options::default()
+        };
+        let e = Engine::open(&test_dir("memtable_kinds"), options).unwrap();
+
+        for i in (0..200).rev() {
+            e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+        }
+        for i in (0..200).step_by(2) {
+            e.put(&format!("key{:03}", i), &format!("{}-new", i))
+                .unwrap();
+        }
+        for i in (0..200).step_by(5) {
+            e.del(&format!("key{:03}", i)).unwrap();
+        }
+        let expected: Vec<(String, String)> = (50..150)
+            .filter(|i| i % 5 != 0)
+            .map(|i| {
+                let val = if i % 2 == 0 {
+                    format!("{}-new", i)
+                } else {
+                    format!("{}", i)
+                };
+                (format!("key{:03}", i), val)
+            })
+            .collect();
+        assert_eq!(expected, e.scan("key050", "key149").unwrap());
+        assert_eq!("7", e.get("key007").unwrap());
+        assert!(e.get("key010").is_err_and(|e| e == MossError::KeyNotFound));
+
+        e.flush().unwrap();
+        assert_eq!(expected, e.scan("key050", "key149").unwrap());
+        assert_eq!("8-new", e.get("key008").unwrap());
+        assert!(e.get("key015").is_err_and(|e| e == MossError::KeyNotFound));
+
+        close_and_clear(&e);
+    }
+}
+
+// close flushes the memtables and rejects later writes, reads still work
+#[test]
+fn test_close() {
+    let options = Options::builder()
+        .memtable_flush_limit(1024 * 1024)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("close"), options).unwrap();
+
+    for i in 0..100 {
+        e.put(&format!("close{:03}", i), &format!("{}", i)).unwrap();
+    }
+    e.close().unwrap();
+    assert!(e.version.read().unwrap().imm_memtables.is_empty());
+    assert!(!e.version.read().unwrap().level(0).is_empty());
+    assert_eq!(Err(MossError::Closed), e.put("close000", "new"));
+    assert_eq!(Err(MossError::Closed), e.del("close001"));
+    assert_eq!("0", e.get("close000").unwrap());
+    assert_eq!(100, e.scan("close000", "close999").unwrap().len());
+    e.close().unwrap();
+
+    // the directory belongs to the next primary, the closed engine doesn't touch its files
+    let dir = e.sstables_dir.clone();
+    let next = Engine::open(&dir, Options::default()).unwrap();
+    let files = log_file_count(&dir);
+    assert!(
+        e.compact_range("close000", "close999")
+            .is_err_and(|err| err.downcast_ref() == Some(&MossError::Closed))
+    );
+    e.purge_obsolete_files();
+    assert_eq!(files, log_file_count(&dir));
+    assert_eq!("0", next.get("close000").unwrap());
+
+    drop(e);
+    close_and_clear(&next);
+}
+
+// oversized keys and values are rejected before the memtable, the flush never fails on them
+#[test]
+fn test_entry_size_limits() {
+    let e = Engine::open(&test_dir("entry_size_limits"), Options::default()).unwrap();
+    let long_key = "k".repeat(40);
+    assert!(matches!(
+        e.put(&long_key, "value"),
+        Err(MossError::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        e.del(&long_key),
+        Err(MossError::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        e.put("key", &"v".repeat(2000)),
+        Err(MossError::InvalidArgument(_))
+    ));
+
+    e.put("key", "value").unwrap();
+    e.flush().unwrap();
+    assert!(e.background_error().is_none());
+    e.put("key", "new").unwrap();
+    assert_eq!("new", e.get("key").unwrap());
+
+    close_and_clear(&e);
+}
+
+// dropping the last reference closes the engine and flushes the memtable
+#[test]
+fn test_drop_flushes() {
+    let dir = test_dir("drop_flushes");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+
+    for i in 0..100 {
+        e.put(&format!("drop{:03}", i), &format!("{}", i)).unwrap();
+    }
+    let weak = Arc::downgrade(&e);
+    drop(e);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(1, log_file_count(&dir));
+
+    remove_dir_all(&dir).unwrap();
+}
+
+// a failed flush rejects writes until the engine is resumed, no data is lost
+#[test]
+fn test_background_error_and_resume() {
+    let dir = test_dir("background_error");
+    let options = Options::builder().memtable_flush_limit(1024).build();
+    let e = Engine::open(&dir, options).unwrap();
+
+    // the flush can't create its file without the directory
+    remove_dir_all(&dir).unwrap();
+    let mut written = 0;
+    let res = loop {
+        let res = e.put(&format!("key{:05}", written), &format!("{}", written));
+        if res.is_err() {
+            break res;
+        }
+        written += 1;
+        sleep(Duration::from_millis(1));
+    };
+    assert!(matches!(res, Err(MossError::BackgroundError(_))));
+    assert!(e.background_error().is_some());
+    assert!(e.flush().is_err());
+    assert!(e.resume().is_err());
+
+    create_dir_all(&dir).unwrap();
+    e.resume().unwrap();
+    assert!(e.background_error().is_none());
+    assert!(e.version.read().unwrap().imm_memtables.is_empty());
+    e.put("after", "resume").unwrap();
+    for i in 0..written {
+        assert_eq!(format!("{}", i), e.get(&format!("key{:05}", i)).unwrap());
+    }
+
+    close_and_clear(&e);
+}
+
+// a metadata file that can't be written keeps the old version, reads go on
+#[test]
+fn test_metadata_write_error() {
+    let dir = test_dir("metadata_write_error");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    e.put("key", "value").unwrap();
+
+    // the temporary metadata file can't be created over a directory
+    let tmp = format!("{}/mossdb_metadata.tmp", dir);
+    create_dir_all(&tmp).unwrap();
+    assert!(e.flush().is_err());
+    assert!(e.background_error().is_some());
+    assert_eq!(1, e.version.read().unwrap().imm_memtables.len());
+    assert_eq!("value", e.get("key").unwrap());
+    assert!(matches!(
+        e.put("key", "other"),
+        Err(MossError::BackgroundError(_))
+    ));
+
+    remove_dir_all(&tmp).unwrap();
+    e.resume().unwrap();
+    assert!(e.version.read().unwrap().imm_memtables.is_empty());
+    assert_eq!("value", e.get("key").unwrap());
+
+    close_and_clear(&e);
+}
+
+// flush returns once the sstable is installed, no sleep needed
+#[test]
+fn test_flush_waits() {
+    let options = Options::builder()
+        .memtable_flush_limit(1024 * 1024)
+        .sstable_compact_limit(10)
+        .build();
+    let e = Engine::open(&test_dir("flush_waits"), options).unwrap();
+
+    for i in 0..100 {
+        e.put(&format!("flushwait{:03}", i), &format!("{}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    {
+        let version = e.version.read().unwrap();
+        assert!(version.imm_memtables.is_empty());
+        assert_eq!(1, version.level(0).len());
+    }
+    e.put("flushwait100", "100").unwrap();
+    e.flush_wait(Duration::from_secs(10)).unwrap();
+    assert_eq!(2, e.version.read().unwrap().level(0).len());
+    // nothing to flush, still waits for the flush thread
+    e.flush_wait(Duration::from_secs(10)).unwrap();
+
+    close_and_clear(&e);
+}
+
+fn is_invalid_argument(res: anyhow::Result<Arc<Engine>>) -> bool {
+    res.is_err_and(|err| {
+        matches!(
+            err.downcast_ref::<MossError>(),
+            Some(MossError::InvalidArgument(_))
+        )
+    })
+}
+
+// options are validated at open, and checked against the ones persisted in the database directory
+#[test]
+fn test_options_validation_and_reopen() {
+    let dir = test_dir("options_validation_and_reopen");
+    let invalid = Options::builder().block_size(0).build();
+    assert!(is_invalid_argument(Engine::open(&dir, invalid)));
+    let missing = Options::builder().create_if_missing(false).build();
+    assert!(is_invalid_argument(Engine::open(&dir, missing)));
+    // the compaction strategy is validated with the other options
+    let mut single_level = Leveled::default();
+    single_level.num_levels = 1;
+    let narrow = SizeTiered {
+        min_merge_width: 4,
+        max_merge_width: 2,
+        ..SizeTiered::default()
+    };
+    for strategy in [
+        Arc::new(single_level) as Arc<dyn CompactionStrategy>,
+        Arc::new(narrow),
+    ] {
+        let options = Options::builder().compaction_strategy(strategy).build();
+        assert!(is_invalid_argument(Engine::open(&dir, options)));
+    }
+    // level 0 strategies never split their outputs
+    let split = Options::builder().target_file_size(Some(4 * 1024)).build();
+    assert!(is_invalid_argument(Engine::open(&dir, split)));
+
+    let leveled = || {
+        Options::builder()
+            .memtable_flush_limit(1024)
+            .compaction_strategy(Arc::new(Leveled::new(1024 * 1024, 10, 4).unwrap()))
+            .block_size(4 * 1024)
+            .build()
+    };
+    let e = Engine::open(&dir, leveled()).unwrap();
+    for i in 0..100 {
+        e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+    }
+    e.close().unwrap();
+    drop(e);
+
+    let exists = Options::builder().error_if_exists(true).build();
+    assert!(is_invalid_argument(Engine::open(&dir, exists)));
+    let fewer_levels = Options::builder()
+        .compaction_strategy(Arc::new(AdjacentPairs))
+        .build();
+    assert!(is_invalid_argument(Engine::open(&dir, fewer_levels)));
+
+    let e = Engine::open(&dir, leveled()).unwrap();
+    for i in 0..100 {
+        assert_eq!(format!("{}", i), e.get(&format!("key{:03}", i)).unwrap());
+    }
+
+    close_and_clear(&e);
+}
+
+// lookups of missing keys are answered by the bloom filter, read blocks go to the block cache
+#[test]
+fn test_bloom_filter_and_block_cache() {
+    let options = Options::builder()
+        .filter_policy(FilterPolicy::Bloom { bits_per_key: 10 })
+        .block_cache_size(1024 * 1024)
+        .build();
+    let e = Engine::open(&test_dir("bloom_filter_and_block_cache"), options).unwrap();
+
+    for i in 0..1000 {
+        e.put(&format!("key{:04}", i * 2), &format!("{}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    for i in 0..1000 {
+        assert_eq!(
+            format!("{}", i),
+            e.get(&format!("key{:04}", i * 2)).unwrap()
+        );
+        assert!(
+            e.get(&format!("key{:04}", i * 2 + 1))
+                .is_err_and(|e| e == MossError::KeyNotFound)
+        );
+    }
+    assert!(e.block_cache_usage() > 0);
+
+    close_and_clear(&e);
+}
+
+// a read-only engine serves the writer's sstables without touching the directory
+#[test]
+fn test_open_read_only() {
+    let dir = test_dir("open_read_only");
+    assert!(is_invalid_argument(Engine::open_read_only(
+        &dir,
+        Options::default()
+    )));
+
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    for i in 0..100 {
+        e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+    }
+    e.flush().unwrap();
+    let files = log_file_count(&dir);
+    eprintln!(
+        "DBG files {} l1 {}",
+        files,
+        e.version.read().unwrap().level(1).len()
+    );
+
+    let r = Engine::open_read_only(&dir, Options::default()).unwrap();
+    assert!(r.is_read_only());
+    assert_eq!("42", r.get("key042").unwrap());
+    assert_eq!(10, r.scan("key010", "key019").unwrap().len());
+    assert!(
+        r.put("key", "value")
+            .is_err_and(|e| e == MossError::ReadOnly)
+    );
+    assert!(r.del("key000").is_err_and(|e| e == MossError::ReadOnly));
+    for res in [r.flush(), r.compact_range("key000", "key099"), r.resume()] {
+        assert!(res.is_err_and(|err| err.downcast_ref() == Some(&MossError::ReadOnly)));
+    }
+    drop(r);
+
+    // nothing was deleted, the writer goes on
+    assert_eq!(files, log_file_count(&dir));
+    e.put("key100", "100").unwrap();
+    assert_eq!("42", e.get("key042").unwrap());
+
+    close_and_clear(&e);
+}
+
+// a secondary follows the primary's flushes and compactions, the files it reads may be removed
+#[test]
+fn test_secondary_catches_up() {
+    let dir = test_dir("secondary_catches_up");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    e.put("key000", "0").unwrap();
+    e.flush().unwrap();
+    assert!(e.try_catch_up_with_primary().is_err());
+
+    // only catches up when asked to
+    let options = Options::builder()
+        .catch_up_interval(Duration::from_secs(3600))
+        .build();
+    let s = Engine::open_as_secondary(&dir, options).unwrap();
+    assert_eq!("0", s.get("key000").unwrap());
+    assert!(
+        s.put("key", "value")
+            .is_err_and(|e| e == MossError::ReadOnly)
+    );
+
+    // the compaction removes the files the secondary still reads
+    e.put("key001", "1").unwrap();
+    e.flush().unwrap();
+    e.compact_range("key000", "key001").unwrap();
+    assert_eq!(1, log_file_count(&dir));
+    assert_eq!("0", s.get("key000").unwrap());
+    assert_eq!(1, s.scan("key000", "key001").unwrap().len());
+    s.try_catch_up_with_primary().unwrap();
+    assert_eq!("1", s.get("key001").unwrap());
+
+    // picked up in the background
+    let options = Options::builder()
+        .catch_up_interval(Duration::from_millis(10))
+        .build();
+    let background = Engine::open_as_secondary(&dir, options).unwrap();
+    e.put("key002", "2").unwrap();
+    e.flush().unwrap();
+    wait_until(|| background.get("key002").is_ok());
+    assert_eq!(3, background.scan("key000", "key002").unwrap().len());
+    assert!(s.get("key002").is_err());
+
+    s.close().unwrap();
+    background.close().unwrap();
+    close_and_clear(&e);
+}
+
+// compacted files are deleted once recorded in the metadata file and no reader holds them,
+// files left behind are collected on the next open
+#[test]
+fn test_obsolete_file_purge() {
+    let dir = test_dir("obsolete_file_purge");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    for i in 0..3 {
+        e.put(&format!("key{}", i), &format!("{}", i)).unwrap();
+        e.flush().unwrap();
+    }
+
+    // the old version of a reader keeps its files
+    let old = Arc::clone(&e.version.read().unwrap());
+    e.compact_range("key0", "key2").unwrap();
+    assert_eq!(4, log_file_count(&dir));
+    let metadata = read_to_string(format!("{}/mossdb_metadata", dir)).unwrap();
+    assert_eq!(
+        3,
+        metadata
+            .lines()
+            .filter(|l| l.starts_with("obsolete "))
+            .count()
+    );
+    e.purge_obsolete_files();
+    assert_eq!(4, log_file_count(&dir));
+    drop(old);
+    e.purge_obsolete_files();
+    assert_eq!(1, log_file_count(&dir));
+
+    // the engine goes away before the reader, like a crash
+    e.put("key3", "3").unwrap();
+    e.flush().unwrap();
+    let old = Arc::clone(&e.version.read().unwrap());
+    e.compact_range("key0", "key3").unwrap();
+    drop(e);
+    drop(old);
+    assert_eq!(3, log_file_count(&dir));
+
+    // only a primary deletes
+    drop(Engine::open_read_only(&dir, Options::default()).unwrap());
+    assert_eq!(3, log_file_count(&dir));
+
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    assert_eq!(1, log_file_count(&dir));
+    let metadata = read_to_string(format!("{}/mossdb_metadata", dir)).unwrap();
+    assert!(!metadata.contains("obsolete "));
+    assert_eq!(4, e.scan("key0", "key3").unwrap().len());
+
+    close_and_clear(&e);
+}
+
+// files no version refers to are quarantined or removed on open, and reported
+#[test]
+fn test_orphan_files_collected_on_open() {
+    let dir = test_dir("orphan_files_collected_on_open");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    assert_eq!(&OrphanReport::default(), e.orphan_report());
+    e.put("key0", "0").unwrap();
+    e.flush().unwrap();
+    drop(e);
+
+    // an output of a flush cut short, a half-written metadata file
+    let orphan = "0190c6e4-0000-7000-8000-000000000000.log";
+    write(format!("{}/{}", dir, orphan), "half written").unwrap();
+    write(format!("{}/mossdb_metadata.tmp", dir), "0 ").unwrap();
+    // files of other programs are never touched
+    write(format!("{}/app.log", dir), "application log").unwrap();
+    write(format!("{}/notes.tmp", dir), "notes").unwrap();
+    let options = Options::builder().quarantine_orphan_files(true).build();
+    let e = Engine::open(&dir, options).unwrap();
+    let report = e.orphan_report();
+    assert_eq!(vec![orphan.to_string()], report.sstables);
+    assert_eq!(vec!["mossdb_metadata.tmp".to_string()], report.temp_files);
+    let quarantine = report.quarantine_dir.clone().unwrap();
+    assert!(metadata(format!("{}/{}", quarantine, orphan)).is_ok());
+    assert!(metadata(format!("{}/app.log", dir)).is_ok());
+    assert!(metadata(format!("{}/notes.tmp", dir)).is_ok());
+    assert_eq!(2, log_file_count(&dir));
+    assert_eq!("0", e.get("key0").unwrap());
+
+    // a second primary would collect the files the first one is writing
+    assert!(Engine::open(&dir, Options::default()).is_err());
+    drop(e);
+
+    write(format!("{}/{}", dir, orphan), "half written").unwrap();
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    assert_eq!(vec![orphan.to_string()], e.orphan_report().sstables);
+    assert_eq!(None, e.orphan_report().quarantine_dir);
+    assert_eq!(2, log_file_count(&dir));
+    assert_eq!("0", e.get("key0").unwrap());
+
+    close_and_clear(&e);
+}
+
+// a read error during a scan is returned instead of a truncated result
+#[test]
+fn test_scan_read_error() {
+    let dir = test_dir("scan_read_error");
+    let e = Engine::open(&dir, Options::default()).unwrap();
+    for i in 0..100 {
+        e.put(&format!("key{:03}", i), &format!("{}", i)).unwrap();
+    }
+    e.flush().unwrap();
+
+    for entry in read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_len(0)
+                .unwrap();
+        }
+    }
+    assert!(e.scan("key000", "key099").is_err());
+
+    close_and_clear(&e);
+}
+
+// a failed subcompaction takes the outputs of the other parts with it
+#[test]
+fn test_failed_subcompaction_removes_outputs() {
+    let dir = test_dir("failed_subcompaction_removes_outputs");
+    let options = Options {
+        memtable_flush_limit: 4 * 1024,
+        block_size: 2 * 1024,
+        compaction_strategy: Arc::new(Leveled::new(1024 * 1024, 10, 3).unwrap()),
+        max_subcompactions: 4,
+        ..Options::default()
+    };
+    let e = Engine::open(&dir, options).unwrap();
+    for i in 0..1000 {
+        e.put(&format!("key{:05}", i), &format!("value{:05}", i))
+            .unwrap();
+    }
+    e.flush().unwrap();
+    e.compact_range("key00000", "key00999").unwrap();
+
+    // only the last part reads the last block
+    let (filename, last_block) = {
+        let version = e.version.read().unwrap();
+        let s = version.level(1).last().unwrap();
+        (s.filename.clone(), s.sparse_index.index.last().unwrap().1)
+    };
+    OpenOptions::new()
+        .write(true)
+        .open(filename)
+        .unwrap()
+        .set_len(last_block)
+        .unwrap();
+    let files = log_file_count(&dir);
+    assert!(e.compact_range("key00000", "key00999").is_err());
+    assert_eq!(files, log_file_count(&dir));
+
+    close_and_clear(&e);
+}
+
+// a newer sstable that can't be read must not let an older value through
+#[test]
+fn test_get_read_error() {
+    let dir = test_dir("get_read_error");
+    let options = Options::builder().sstable_compact_limit(100).build();
+    let e = Engine::open(&dir, options).unwrap();
+    // several blocks, the sstable keeps only its last block in memory
+    for value in ["old", "new"] {
+        for i in 0..100 {
+            e.put(&format!("key{:03}", i), &value.repeat(100)).unwrap();
+        }
+        e.flush().unwrap();
+    }
+
+    // file names sort by creation time
+    let newest = read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .max()
+        .unwrap();
+    OpenOptions::new()
+        .write(true)
+        .open(newest)
+        .unwrap()
+        .set_len(0)
+        .unwrap();
+    assert!(matches!(e.get("key000"), Err(MossError::ReadError(_))));
+
+    close_and_clear(&e);
+}
+
+// overwritten values stay in the skiplist arena, so overwrites alone fill the memtable
+#[test]
+fn test_overwrites_fill_memtable() {
+    let dir = test_dir("overwrites_fill_memtable");
+    let options = Options::builder()
+        .memtable_flush_limit(64 * 1024)
+        .sstable_compact_limit(100)
+        .build();
+    let e = Engine::open(&dir, options).unwrap();
+    let value = "v".repeat(100);
+    for _ in 0..10_000 {
+        e.put("key", &value).unwrap();
+    }
+    e.flush().unwrap();
+    assert!(log_file_count(&dir) > 1);
+    assert_eq!(value, e.get("key").unwrap());
+
+    close_and_clear(&e);
 }